@@ -0,0 +1,123 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    container_type::ContainerType, local_sink::data_dirs, manifest::FileManifest,
+    protocol::EntryFile, search_provider::ini_value, server::rewrite_exec, sink::EntrySink,
+};
+
+/// the bus name a D-Bus service file activates
+pub fn service_name(text: &str) -> Option<&str> {
+    ini_value(text, "Name")
+}
+
+/// whether `path` is a D-Bus service file in a session services dir
+pub fn is_service(path: &Path) -> bool {
+    path.parent()
+        .is_some_and(|dir| dir.ends_with("dbus-1/services"))
+        && path.extension().and_then(|ext| ext.to_str()) == Some("service")
+}
+
+/// bus names the services in the host's `XDG_DATA_DIRS` activate. a user service file
+/// would override them and send the host's own app into the container
+pub fn host_bus_names() -> HashSet<String> {
+    let mut names = HashSet::new();
+    for dir in data_dirs() {
+        let Ok(read_dir) = fs::read_dir(dir.join("dbus-1").join("services")) else {
+            continue;
+        };
+        let paths = read_dir.flatten().map(|dir_entry| dir_entry.path());
+        for path in paths.filter(|path: &PathBuf| is_service(path)) {
+            if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+                names.insert(stem.to_string());
+            }
+            if let Some(name) = fs::read_to_string(&path)
+                .ok()
+                .as_deref()
+                .and_then(service_name)
+            {
+                names.insert(name.to_string());
+            }
+        }
+    }
+    names
+}
+
+/// install host service files into `dbus-1/services` of the sink's data dir that start
+/// the services of `container_name` named in `bus_names` inside the container,
+/// replacing the ones installed before
+pub fn export_services(
+    sink: &dyn EntrySink,
+    container_name: &str,
+    container_type: ContainerType,
+    services: &[EntryFile],
+    bus_names: &HashSet<String>,
+) -> Result<(), io::Error> {
+    let services_dir = sink
+        .data_dir(container_name)?
+        .join("dbus-1")
+        .join("services");
+    let mut manifest = FileManifest::load("dbus-services")?;
+    manifest.remove_container(container_name);
+    let host_names = host_bus_names();
+    let mut activated = HashSet::new();
+    for service in services.iter() {
+        let Ok(text) = std::str::from_utf8(&service.data) else {
+            continue;
+        };
+        let Some(name) = service_name(text).filter(|name| bus_names.contains(*name)) else {
+            continue;
+        };
+        if !activated.insert(name.to_string()) {
+            continue;
+        }
+        if host_names.contains(name) {
+            log::info!(
+                "{}: not activating {}, the host already provides it",
+                container_name,
+                name
+            );
+            continue;
+        }
+        // The bus reads the file by the name it activates. systemd units in the
+        // container don't exist on the host, so the service is always started with Exec
+        let text: Vec<&str> = text
+            .lines()
+            .filter(|line| {
+                !line
+                    .split_once('=')
+                    .is_some_and(|(key, _)| ["SystemdService", "User"].contains(&key.trim()))
+            })
+            .collect();
+        let text = rewrite_exec(container_name, container_type, &(text.join("\n") + "\n"));
+        let path = services_dir.join(format!("{}.service", name));
+        if path.symlink_metadata().is_ok() {
+            log::warn!(
+                "{}: not activating {}, {:?} already exists",
+                container_name,
+                name,
+                path
+            );
+            continue;
+        }
+        manifest.write(container_name, &path, text.as_bytes())?;
+    }
+    for name in bus_names.difference(&activated) {
+        log::warn!(
+            "{}: no D-Bus service file for {}, it won't be started on demand",
+            container_name,
+            name
+        );
+    }
+    manifest.save()
+}
+
+/// remove the service files installed for `container_name`
+pub fn remove_services(container_name: &str) -> Result<(), io::Error> {
+    let mut manifest = FileManifest::load("dbus-services")?;
+    manifest.remove_container(container_name);
+    manifest.save()
+}
@@ -0,0 +1,36 @@
+use std::io;
+
+use crate::{
+    local_sink::{config_home, namespaced},
+    manifest::FileManifest,
+};
+
+/// write the autostart entries of `container_name` into `$XDG_CONFIG_HOME/autostart`,
+/// replacing the ones written before. `entries` are appids and rewritten file texts
+pub fn export_autostart(
+    container_name: &str,
+    entries: &[(String, String)],
+) -> Result<(), io::Error> {
+    let autostart_dir = config_home()?.join("autostart");
+    let mut manifest = FileManifest::load("autostart")?;
+    manifest.remove_container(container_name);
+    for (appid, file_text) in entries.iter() {
+        let path = autostart_dir.join(format!("{}.desktop", namespaced(container_name, appid)));
+        manifest.write(container_name, &path, file_text.as_bytes())?;
+    }
+    if !entries.is_empty() {
+        log::info!(
+            "{}: exported {} autostart entries",
+            container_name,
+            entries.len()
+        );
+    }
+    manifest.save()
+}
+
+/// remove the autostart entries written for `container_name`
+pub fn remove_autostart(container_name: &str) -> Result<(), io::Error> {
+    let mut manifest = FileManifest::load("autostart")?;
+    manifest.remove_container(container_name);
+    manifest.save()
+}
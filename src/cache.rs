@@ -0,0 +1,302 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::EntryLifetime;
+
+/// content-addressed store of files fetched from containers, kept between runs
+/// under `$XDG_CACHE_HOME/container-desktop-entries`
+pub struct Cache {
+    path: PathBuf,
+    /// nothing is written, for dry runs
+    read_only: bool,
+}
+
+/// hashes of the files a container had the last time it was synced, keyed by
+/// their path in the container
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    pub desktop_files: HashMap<PathBuf, String>,
+    pub icons: HashMap<PathBuf, String>,
+    #[serde(default)]
+    pub mime_packages: HashMap<PathBuf, String>,
+    #[serde(default)]
+    pub metainfo: HashMap<PathBuf, String>,
+    #[serde(default)]
+    pub autostart: HashMap<PathBuf, String>,
+    #[serde(default)]
+    pub search_providers: HashMap<PathBuf, String>,
+    #[serde(default)]
+    pub dbus_services: HashMap<PathBuf, String>,
+}
+
+/// what has been registered with the daemon. session entries are tracked in the
+/// runtime directory, so they go away together with the daemon's session entries.
+/// persistent entries are tracked in the state directory. process entries are
+/// only tracked in memory
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PushedState {
+    pub containers: HashMap<String, PushedEntries>,
+}
+
+/// hashes of the entry text and icon data registered for one container, keyed by
+/// appid and icon name respectively
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct PushedEntries {
+    #[serde(default)]
+    pub lifetime: EntryLifetime,
+    pub entries: HashMap<String, String>,
+    pub icons: HashMap<String, String>,
+}
+
+/// when each container was last synced and whether that worked, kept in the
+/// state directory for `status`
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SyncStatus {
+    pub containers: HashMap<String, ContainerStatus>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct ContainerStatus {
+    /// seconds since the unix epoch
+    pub last_sync: u64,
+    pub error: Option<String>,
+}
+
+impl Cache {
+    pub fn open() -> Result<Self, io::Error> {
+        let cache = Self::open_read_only()?;
+        fs::create_dir_all(cache.path.join("blobs"))?;
+        fs::create_dir_all(cache.path.join("manifests"))?;
+        Ok(Self {
+            read_only: false,
+            ..cache
+        })
+    }
+
+    /// open the cache without ever writing to it, files that would be stored are dropped
+    pub fn open_read_only() -> Result<Self, io::Error> {
+        let cache_home = env::var("XDG_CACHE_HOME")
+            .or(env::var("HOME").map(|home| format!("{}/.cache", home)))
+            .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+        let path = PathBuf::from(cache_home).join("container-desktop-entries");
+        Ok(Self {
+            path,
+            read_only: true,
+        })
+    }
+
+    /// get a previously stored file by the hash of its contents
+    pub fn get(&self, hash: &str) -> Option<Vec<u8>> {
+        fs::read(self.path.join("blobs").join(hash)).ok()
+    }
+
+    /// store `data`, returning the hash it can be retrieved with
+    pub fn put(&self, data: &[u8]) -> Result<String, io::Error> {
+        let hash = hash_bytes(data);
+        let path = self.path.join("blobs").join(&hash);
+        if !self.read_only && !path.exists() {
+            // write then rename, so a half written blob is never picked up
+            let tmp_path = path.with_extension("tmp");
+            fs::write(&tmp_path, data)?;
+            fs::rename(tmp_path, path)?;
+        }
+        Ok(hash)
+    }
+
+    pub fn manifest(&self, container_name: &str) -> Manifest {
+        read_json(&self.manifest_path(container_name)).unwrap_or_default()
+    }
+
+    pub fn save_manifest(
+        &self,
+        container_name: &str,
+        manifest: &Manifest,
+    ) -> Result<(), io::Error> {
+        if self.read_only {
+            return Ok(());
+        }
+        write_json(&self.manifest_path(container_name), manifest)
+    }
+
+    /// drop manifests of containers other than `containers`, then every blob
+    /// no remaining manifest refers to
+    pub fn collect_garbage(&self, containers: &HashSet<&str>) -> Result<(), io::Error> {
+        let mut used = HashSet::new();
+        for entry in fs::read_dir(self.path.join("manifests"))? {
+            let path = entry?.path();
+            let name = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or("");
+            if !containers.contains(name) {
+                log::info!("Removing cache manifest for {}", name);
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let manifest: Manifest = read_json(&path).unwrap_or_default();
+            used.extend(manifest.desktop_files.into_values());
+            used.extend(manifest.icons.into_values());
+            used.extend(manifest.mime_packages.into_values());
+            used.extend(manifest.metainfo.into_values());
+            used.extend(manifest.autostart.into_values());
+            used.extend(manifest.search_providers.into_values());
+            used.extend(manifest.dbus_services.into_values());
+        }
+        for entry in fs::read_dir(self.path.join("blobs"))? {
+            let path = entry?.path();
+            if !path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| used.contains(name))
+            {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn manifest_path(&self, container_name: &str) -> PathBuf {
+        self.path
+            .join("manifests")
+            .join(format!("{}.json", container_name))
+    }
+}
+
+impl PushedState {
+    pub fn load() -> Self {
+        let mut state: Self = read_json(&runtime_dir().join("pushed.json")).unwrap_or_default();
+        let persistent: Self = state_dir()
+            .and_then(|dir| read_json(&dir.join("persistent.json")))
+            .unwrap_or_default();
+        state.containers.extend(persistent.containers);
+        state
+    }
+
+    pub fn save(&self) -> Result<(), io::Error> {
+        let dir = runtime_dir();
+        fs::create_dir_all(&dir)?;
+        write_json(
+            &dir.join("pushed.json"),
+            &self.with_lifetime(EntryLifetime::Session),
+        )?;
+        let dir = state_dir().ok_or(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no state directory",
+        ))?;
+        fs::create_dir_all(&dir)?;
+        write_json(
+            &dir.join("persistent.json"),
+            &self.with_lifetime(EntryLifetime::Persistent),
+        )
+    }
+
+    fn with_lifetime(&self, lifetime: EntryLifetime) -> Self {
+        Self {
+            containers: self
+                .containers
+                .iter()
+                .filter(|(_, pushed)| pushed.lifetime == lifetime)
+                .map(|(name, pushed)| (name.clone(), pushed.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl SyncStatus {
+    pub fn load() -> Self {
+        state_dir()
+            .and_then(|dir| read_json(&dir.join("status.json")))
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), io::Error> {
+        let dir = state_dir().ok_or(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no state directory",
+        ))?;
+        fs::create_dir_all(&dir)?;
+        write_json(&dir.join("status.json"), self)
+    }
+}
+
+/// default applications set in mimeapps.list on behalf of containers, so they can
+/// be unset again. kept in the state directory
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct MimeDefaults {
+    /// mime type and desktop file id, by container
+    pub containers: HashMap<String, BTreeMap<String, String>>,
+}
+
+impl MimeDefaults {
+    pub fn load() -> Self {
+        state_dir()
+            .and_then(|dir| read_json(&dir.join("mime-defaults.json")))
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), io::Error> {
+        let dir = state_dir().ok_or(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no state directory",
+        ))?;
+        fs::create_dir_all(&dir)?;
+        write_json(&dir.join("mime-defaults.json"), self)
+    }
+}
+
+/// write the reports of the last sync to `report.json` in the runtime directory
+pub fn save_report<T: Serialize + ?Sized>(reports: &T) -> Result<(), io::Error> {
+    let dir = runtime_dir();
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("report.json"), serde_json::to_vec_pretty(reports)?)
+}
+
+/// runtime directory of the service, removed by systemd when it stops
+pub fn runtime_dir() -> PathBuf {
+    match env::var("RUNTIME_DIRECTORY") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => PathBuf::from(env::var("XDG_RUNTIME_DIR").unwrap_or(format!(
+            "/run/user/{}",
+            env::var("UID").unwrap_or("1000".to_string())
+        )))
+        .join("container-desktop-entries"),
+    }
+}
+
+/// `$XDG_STATE_HOME/container-desktop-entries`, for state that must survive
+/// logins but not be thrown away like the cache
+pub fn state_dir() -> Option<PathBuf> {
+    let state_home = env::var("XDG_STATE_HOME")
+        .or(env::var("HOME").map(|home| format!("{}/.local/state", home)))
+        .ok()?;
+    Some(PathBuf::from(state_home).join("container-desktop-entries"))
+}
+
+/// hex encoded sha256 of `data`, matching the output of `sha256sum`
+pub fn hash_bytes(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let data = fs::read(path).ok()?;
+    match serde_json::from_slice(&data) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            log::warn!("Ignoring invalid cache file {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), io::Error> {
+    fs::write(path, serde_json::to_vec(value)?)
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use zbus::{fdo::DBusProxy, names::BusName, Connection};
+
+use crate::{
+    cache::{PushedState, SyncStatus},
+    server::{self, ClientSetupError, ContainerReport, Server},
+    service::{ServiceProxy, SERVICE_NAME},
+    sink, Backend, ContainerList, EntryLifetime, ReportFormat,
+};
+
+/// sync `names`, or every container if empty. goes through the running service
+/// if there is one, so it keeps track of what was pushed
+pub async fn sync(
+    containers: ContainerList,
+    names: Vec<String>,
+    owner: &str,
+    report: Option<ReportFormat>,
+) -> Result<(), ClientSetupError> {
+    check_configured(&containers, &names)?;
+    if let Some(proxy) = running_service().await {
+        let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+        let json = proxy.sync_report(&names).await?;
+        match report {
+            Some(ReportFormat::Json) => println!("{}", json),
+            None => {
+                let reports: Vec<ContainerReport> =
+                    serde_json::from_str(&json).map_err(std::io::Error::from)?;
+                print_reports(&reports);
+            }
+        }
+        return Ok(());
+    }
+    refuse_process_entries(&containers, &names)?;
+    let mut server = Server::new(containers, owner).await?;
+    let reports = if names.is_empty() {
+        server.sync_all().await?
+    } else {
+        server.sync(&names).await?
+    };
+    match report {
+        Some(ReportFormat::Json) => print_json(&reports),
+        None => print_reports(&reports),
+    }
+    Ok(())
+}
+
+/// gather the entries of `names`, or every container if empty, and print them
+/// as they would be registered, or write them to `output` if given. nothing is
+/// registered or removed. entries are shown with the file names and `Icon=` the
+/// backend would give them. with `report`, the report is printed instead of the entries
+pub async fn dry_run(
+    containers: ContainerList,
+    names: Vec<String>,
+    owner: &str,
+    output: Option<PathBuf>,
+    report: Option<ReportFormat>,
+) -> Result<(), ClientSetupError> {
+    check_configured(&containers, &names)?;
+    let sink = sink::open_sink(containers.backend, owner).await?;
+    let names: HashSet<&str> = if names.is_empty() {
+        containers
+            .containers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect()
+    } else {
+        names.iter().map(|name| name.as_str()).collect()
+    };
+    let setups = server::set_up_clients(&containers, &names, true).await?;
+    if report.is_some() {
+        let reports: Vec<ContainerReport> = setups.iter().map(|setup| setup.report()).collect();
+        print_json(&reports);
+    }
+    for setup in setups.iter() {
+        let container_name = &setup.container;
+        let entries = match setup.result.as_ref() {
+            Ok(client) => &client.entries,
+            Err(e) => {
+                if report.is_none() {
+                    println!("{}: failed: {}", container_name, e);
+                }
+                continue;
+            }
+        };
+        for entry in entries.iter() {
+            let icon_path = match entry.icon_path.as_ref() {
+                Some(path) => format!("{:?}", path),
+                None => "placeholder".to_string(),
+            };
+            let desktop_id = sink.desktop_id(container_name, &entry.appid);
+            let icon_name = sink.icon_name(container_name, &entry.icon_name);
+            let file_text = sink.entry_text(container_name, &entry.file_text);
+            match output.as_ref() {
+                Some(output) => {
+                    let dir = output.join(container_name);
+                    let entry_path = dir.join(&desktop_id);
+                    let icon_file = dir.join("icons").join(format!(
+                        "{}.{}",
+                        icon_name.trim_start_matches('/').replace('/', "_"),
+                        if image::guess_format(&entry.icon_data).is_ok() {
+                            "png"
+                        } else {
+                            "svg"
+                        }
+                    ));
+                    fs::create_dir_all(icon_file.parent().unwrap())?;
+                    fs::write(&entry_path, &file_text)?;
+                    fs::write(&icon_file, &entry.icon_data)?;
+                    if report.is_some() {
+                        continue;
+                    }
+                    println!(
+                        "{}: {} -> {:?}, icon {} -> {:?}",
+                        container_name, desktop_id, entry_path, icon_path, icon_file
+                    );
+                }
+                None if report.is_some() => {}
+                None => {
+                    println!("# {}: {}", container_name, desktop_id);
+                    println!("# icon '{}' from {}", icon_name, icon_path);
+                    println!("{}", file_text.trim_end());
+                    println!();
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// remove the entries of `name` until it is synced again
+pub async fn remove(
+    containers: ContainerList,
+    name: String,
+    owner: &str,
+) -> Result<(), ClientSetupError> {
+    check_configured(&containers, std::slice::from_ref(&name))?;
+    if let Some(proxy) = running_service().await {
+        proxy.remove(&name).await?;
+        println!("Removed {} through the running service", name);
+        return Ok(());
+    }
+    let report = Server::new(containers, owner).await?.remove(&name).await?;
+    print_reports(&[report]);
+    Ok(())
+}
+
+/// print every configured container along with the apps exported from it
+pub fn list(containers: &ContainerList) {
+    let pushed = PushedState::load();
+    for (name, container_type) in containers.containers.iter() {
+        println!("{} ({:?})", name, container_type);
+        let mut appids: Vec<&String> = pushed
+            .containers
+            .get(name)
+            .map(|pushed| pushed.entries.keys().collect())
+            .unwrap_or_default();
+        appids.sort();
+        for appid in appids {
+            println!("  {}", appid);
+        }
+    }
+}
+
+/// print when each configured container was last synced and whether it worked
+pub async fn status(containers: &ContainerList) {
+    match running_service().await {
+        Some(_) => println!("Service: running"),
+        None => println!("Service: not running"),
+    }
+    let status = SyncStatus::load();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    for (name, _) in containers.containers.iter() {
+        match status.containers.get(name) {
+            None => println!("{}: never synced", name),
+            Some(container) => {
+                let ago = format_elapsed(now.saturating_sub(container.last_sync));
+                match container.error.as_ref() {
+                    None => println!("{}: synced {} ago", name, ago),
+                    Some(e) => println!("{}: failed {} ago: {}", name, ago, e),
+                }
+            }
+        }
+    }
+}
+
+/// the running service, if there is one and the session bus can be reached
+async fn running_service() -> Option<ServiceProxy<'static>> {
+    let connection = match Connection::session().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            log::debug!("No session bus: {}", e);
+            return None;
+        }
+    };
+    let dbus = DBusProxy::new(&connection).await.ok()?;
+    let name = BusName::from_static_str(SERVICE_NAME).unwrap();
+    if !dbus.name_has_owner(name).await.unwrap_or(false) {
+        return None;
+    }
+    ServiceProxy::new(&connection).await.ok()
+}
+
+fn check_configured(containers: &ContainerList, names: &[String]) -> Result<(), ClientSetupError> {
+    for name in names.iter() {
+        if !containers.containers.iter().any(|(other, _)| other == name) {
+            return Err(ClientSetupError::IO(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} is not a configured container", name),
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// entries with the Process lifetime are removed when this command exits, so
+/// without the service running, syncing them would do nothing
+fn refuse_process_entries(
+    containers: &ContainerList,
+    names: &[String],
+) -> Result<(), ClientSetupError> {
+    if matches!(containers.backend, Backend::Local | Backend::Export) {
+        return Ok(());
+    }
+    for (name, _) in containers.containers.iter() {
+        if (names.is_empty() || names.contains(name))
+            && containers.settings(name).lifetime == EntryLifetime::Process
+        {
+            return Err(ClientSetupError::IO(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!(
+                    "the entries of {} would be removed when this command exits, start the service to sync them",
+                    name
+                ),
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn print_reports(reports: &[ContainerReport]) {
+    for report in reports.iter() {
+        match report.error.as_ref() {
+            Some(e) => println!("{}: failed: {}", report.container, e),
+            None => println!(
+                "{}: {} added, {} removed",
+                report.container,
+                report.added.len(),
+                report.removed.len()
+            ),
+        }
+    }
+}
+
+fn print_json(reports: &[ContainerReport]) {
+    println!("{}", serde_json::to_string_pretty(reports).unwrap());
+}
+
+fn format_elapsed(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
@@ -0,0 +1,533 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs, io,
+    os::fd::{AsRawFd, BorrowedFd},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use async_std::{
+    future,
+    io::{BufRead, BufReader},
+    os::unix::net::{UnixListener, UnixStream},
+    task,
+};
+use freedesktop_desktop_entry::DesktopEntry;
+use futures_util::StreamExt;
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use walkdir::WalkDir;
+use zbus::object_server::InterfaceRef;
+
+use crate::{
+    activation,
+    cache::runtime_dir,
+    icon, metainfo,
+    protocol::{
+        negotiate, read_message, write_message, Bundle, ClientMessage, ContainerMetadata,
+        EntryFile, IconBlob, MetainfoFile, MimePackage, ServerMessage, MIN_PROTOCOL_VERSION,
+        PROTOCOL_VERSION,
+    },
+    search_provider,
+    server::ClientSetupError,
+    service::ContainerDesktopEntries,
+};
+
+/// how long the host waits for the next message of a client before hanging up
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// socket the host listens on for bundles. the runtime dir is shared with
+/// toolbox containers, so both sides find it at the same path
+pub fn socket_path() -> PathBuf {
+    runtime_dir().join("client.sock")
+}
+
+/// run inside a container: gather its entries and send them to the host
+pub async fn client(name: Option<String>) -> Result<(), ClientSetupError> {
+    let container = ContainerMetadata {
+        name: match name {
+            Some(name) => name,
+            None => container_name()?,
+        },
+        os: os_name(),
+        client_version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    let messages = gather(&container)?;
+    let stream = UnixStream::connect(socket_path()).await?;
+    let mut reader = BufReader::new(stream.clone());
+    let mut writer = stream;
+
+    write_message(
+        &mut writer,
+        &ClientMessage::Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            container,
+        },
+    )
+    .await?;
+    let version = match read_message(&mut reader).await? {
+        Some(ServerMessage::Welcome { version }) => {
+            log::debug!("Host speaks protocol version {}", version);
+            version
+        }
+        Some(ServerMessage::Rejected { reason }) => {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, reason).into())
+        }
+        other => return Err(unexpected(other).into()),
+    };
+    for message in messages.iter() {
+        if message.min_version() > version {
+            log::warn!(
+                "Host speaks protocol version {}, not sending a message that needs version {}",
+                version,
+                message.min_version()
+            );
+            continue;
+        }
+        write_message(&mut writer, message).await?;
+    }
+    write_message(&mut writer, &ClientMessage::Done).await?;
+    let report = match read_message(&mut reader).await? {
+        Some(ServerMessage::Report(report)) => report,
+        other => return Err(unexpected(other).into()),
+    };
+    match report.error {
+        Some(e) => println!("{}: failed: {}", report.container, e),
+        None => println!(
+            "{}: {} exported, {} added, {} removed",
+            report.container,
+            report.exported.len(),
+            report.added.len(),
+            report.removed.len()
+        ),
+    }
+    Ok(())
+}
+
+fn unexpected(message: Option<ServerMessage>) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected reply from host: {:?}", message),
+    )
+}
+
+/// `PRETTY_NAME` from the os-release of the container
+fn os_name() -> Option<String> {
+    let os_release = fs::read_to_string("/etc/os-release")
+        .or(fs::read_to_string("/usr/lib/os-release"))
+        .ok()?;
+    os_release
+        .lines()
+        .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+        .map(|name| name.trim_matches('"').to_string())
+}
+
+/// name of the container we run in, from the file podman puts in every container
+fn container_name() -> Result<String, io::Error> {
+    let containerenv = fs::read_to_string("/run/.containerenv")?;
+    containerenv_name(&containerenv).ok_or(io::Error::new(
+        io::ErrorKind::NotFound,
+        "no container name in /run/.containerenv, pass --name",
+    ))
+}
+
+/// the container name in the text of a `.containerenv` file
+fn containerenv_name(containerenv: &str) -> Option<String> {
+    containerenv
+        .lines()
+        .find_map(|line| line.strip_prefix("name="))
+        .map(|name| name.trim_matches('"').to_string())
+}
+
+/// read the desktop files and mime packages of every data dir along with the icons
+/// the desktop files use. earlier data dirs take precedence, and themed icons are
+/// preferred over pixmaps
+fn gather(container: &ContainerMetadata) -> Result<Vec<ClientMessage>, io::Error> {
+    let data_dirs = env::var("XDG_DATA_DIRS").unwrap_or("/usr/local/share:/usr/share".to_string());
+    let data_dirs: Vec<&Path> = data_dirs.split(':').map(Path::new).collect();
+    let mut entries = Vec::new();
+    let mut seen = HashSet::new();
+    let mut icon_names = HashSet::new();
+    let mut activatable = HashSet::new();
+    for dir in data_dirs.iter() {
+        let Ok(read_dir) = fs::read_dir(dir.join("applications")) else {
+            continue;
+        };
+        for entry in read_dir {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("desktop")
+                || !seen.insert(path.file_name().unwrap().to_owned())
+            {
+                continue;
+            }
+            let data = fs::read(&path)?;
+            if let Some(entry) = std::str::from_utf8(&data)
+                .ok()
+                .and_then(|text| DesktopEntry::decode(&path, text).ok())
+            {
+                if let Some(icon_name) = entry.icon() {
+                    icon_names.insert(icon_name.to_string());
+                }
+                if entry.desktop_entry("DBusActivatable") == Some("true") {
+                    activatable.insert(entry.appid.to_string());
+                }
+            }
+            entries.push(EntryFile { path, data });
+        }
+    }
+
+    let mut mime_packages = Vec::new();
+    let mut seen = HashSet::new();
+    for dir in data_dirs.iter() {
+        let Ok(read_dir) = fs::read_dir(dir.join("mime").join("packages")) else {
+            continue;
+        };
+        for entry in read_dir {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some("xml")
+                && seen.insert(path.file_name().unwrap().to_owned())
+            {
+                mime_packages.push(MimePackage {
+                    data: fs::read(&path)?,
+                    path,
+                });
+            }
+        }
+    }
+
+    // metainfo is matched to desktop files by name, and preferred over appdata
+    let desktop_ids: HashSet<String> = entries
+        .iter()
+        .filter_map(|entry| Some(entry.path.file_stem()?.to_str()?.to_string()))
+        .collect();
+    let mut metainfo = Vec::new();
+    let mut seen = HashSet::new();
+    for dir in data_dirs
+        .iter()
+        .flat_map(|dir| [dir.join("metainfo"), dir.join("appdata")])
+    {
+        let Ok(read_dir) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in read_dir {
+            let path = entry?.path();
+            let Some(appid) = metainfo::appid(&path) else {
+                continue;
+            };
+            if desktop_ids.contains(appid) && seen.insert(appid.to_string()) {
+                metainfo.push(MetainfoFile {
+                    data: fs::read(&path)?,
+                    path,
+                });
+            }
+        }
+    }
+
+    // toolbox containers share the home directory, whose autostart entries the
+    // host already runs itself
+    let mut autostart_dirs: Vec<PathBuf> = Vec::new();
+    if !Path::new("/run/.toolboxenv").exists() {
+        if let Ok(config_home) =
+            env::var("XDG_CONFIG_HOME").or(env::var("HOME").map(|home| format!("{}/.config", home)))
+        {
+            autostart_dirs.push(Path::new(&config_home).join("autostart"));
+        }
+    }
+    autostart_dirs.extend(
+        env::var("XDG_CONFIG_DIRS")
+            .unwrap_or("/etc/xdg".to_string())
+            .split(':')
+            .map(|dir| Path::new(dir).join("autostart")),
+    );
+    let mut autostart = Vec::new();
+    let mut seen = HashSet::new();
+    for dir in autostart_dirs.iter() {
+        let Ok(read_dir) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in read_dir {
+            let path = entry?.path();
+            let file_name = path.file_name().unwrap().to_owned();
+            // Entries we exported to the host before, should the home directory be shared
+            if path.extension().and_then(|ext| ext.to_str()) == Some("desktop")
+                && !file_name
+                    .to_string_lossy()
+                    .starts_with("container-desktop-entries-")
+                && seen.insert(file_name)
+            {
+                autostart.push(EntryFile {
+                    data: fs::read(&path)?,
+                    path,
+                });
+            }
+        }
+    }
+
+    let mut search_providers = Vec::new();
+    let mut seen = HashSet::new();
+    for dir in data_dirs.iter().flat_map(|dir| {
+        [
+            dir.join("gnome-shell").join("search-providers"),
+            dir.join("krunner").join("dbusplugins"),
+        ]
+    }) {
+        let Ok(read_dir) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in read_dir {
+            let path = entry?.path();
+            if search_provider::is_provider(&path)
+                && seen.insert(path.file_name().unwrap().to_owned())
+            {
+                search_providers.push(EntryFile {
+                    data: fs::read(&path)?,
+                    path,
+                });
+            }
+        }
+    }
+    // Only the services that start the search providers and D-Bus activatable apps are sent
+    let bus_names: HashSet<String> = search_providers
+        .iter()
+        .filter_map(|provider| {
+            search_provider::bus_name(&provider.path, std::str::from_utf8(&provider.data).ok()?)
+        })
+        .chain(activatable)
+        .collect();
+    let mut dbus_services = Vec::new();
+    let mut seen = HashSet::new();
+    for dir in data_dirs.iter() {
+        let Ok(read_dir) = fs::read_dir(dir.join("dbus-1").join("services")) else {
+            continue;
+        };
+        for entry in read_dir {
+            let path = entry?.path();
+            if activation::is_service(&path)
+                && path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .is_some_and(|stem| bus_names.contains(stem))
+                && seen.insert(path.file_name().unwrap().to_owned())
+            {
+                dbus_services.push(EntryFile {
+                    data: fs::read(&path)?,
+                    path,
+                });
+            }
+        }
+    }
+
+    let pixmaps = Path::new("/usr/share/pixmaps");
+    let mut search_dirs: Vec<PathBuf> = data_dirs.iter().map(|dir| dir.join("icons")).collect();
+    search_dirs.push(pixmaps.to_path_buf());
+    let mut best: HashMap<String, ((bool, u32), PathBuf)> = HashMap::new();
+    for name in icon_names.iter().filter(|name| name.starts_with('/')) {
+        // Icon= may already be an absolute path
+        best.insert(name.clone(), ((true, 0), PathBuf::from(name)));
+    }
+    for dir in search_dirs.iter() {
+        for entry in WalkDir::new(dir).follow_links(true).into_iter().flatten() {
+            let path = entry.path();
+            let Some(name) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .filter(|stem| icon_names.contains(*stem))
+            else {
+                continue;
+            };
+            let Some(rank) = icon::icon_rank(path) else {
+                continue;
+            };
+            let key = (!path.starts_with(pixmaps), rank);
+            if best.get(name).is_none_or(|(best_key, _)| key > *best_key) {
+                best.insert(name.to_string(), (key, path.to_path_buf()));
+            }
+        }
+    }
+    let mut icons = Vec::new();
+    for (name, ((_, rank), path)) in best {
+        match fs::read(&path) {
+            Ok(data) => icons.push(IconBlob {
+                name,
+                size: icon_size(&path),
+                scalable: rank == u32::MAX,
+                path,
+                data,
+            }),
+            Err(e) => log::warn!("Could not read icon {:?}: {}", path, e),
+        }
+    }
+    log::info!(
+        "{}: sending {} desktop files, {} icons, {} mime packages, {} metainfo files, {} autostart entries and {} search providers",
+        container.name,
+        entries.len(),
+        icons.len(),
+        mime_packages.len(),
+        metainfo.len(),
+        autostart.len(),
+        search_providers.len()
+    );
+    Ok(vec![
+        ClientMessage::Entries(entries),
+        ClientMessage::Icons(icons),
+        ClientMessage::MimePackages(mime_packages),
+        ClientMessage::Metainfo(metainfo),
+        ClientMessage::Autostart(autostart),
+        ClientMessage::SearchProviders(search_providers),
+        ClientMessage::DBusServices(dbus_services),
+    ])
+}
+
+/// nominal size of an icon in a theme directory like `48x48/apps`
+fn icon_size(path: &Path) -> Option<u32> {
+    let (size, _) = path
+        .parent()?
+        .parent()?
+        .file_name()?
+        .to_str()?
+        .split_once('x')?;
+    size.parse().ok()
+}
+
+/// accept bundles from clients and register their entries
+pub async fn serve_clients(iface: InterfaceRef<ContainerDesktopEntries>) -> Result<(), io::Error> {
+    let path = socket_path();
+    fs::create_dir_all(path.parent().unwrap())?;
+    // A socket left behind by a previous run would make bind fail
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).await?;
+    log::info!("Listening for clients on {:?}", path);
+    task::spawn(async move {
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    // A client that stalls must not hold up the others
+                    let iface = iface.clone();
+                    task::spawn(async move {
+                        if let Err(e) = handle_client(&iface, stream).await {
+                            log::error!("Error handling client: {}", e);
+                        }
+                    });
+                }
+                Err(e) => log::error!("Could not accept client: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// check that the client on `stream` runs in the container it claims, so one
+/// container can't replace the entries of another. podman and toolbox write the
+/// name to `/run/.containerenv`, which the host reads through the client's pid.
+/// clients whose container can't be looked into are only let through when
+/// `accept_unverified` is set for the container
+fn verify_container(
+    stream: &UnixStream,
+    claimed: &str,
+    accept_unverified: bool,
+) -> Result<(), String> {
+    // SAFETY: `stream` owns the fd and outlives the borrow
+    let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+    let pid = getsockopt(&fd, PeerCredentials)
+        .map_err(|e| format!("could not get client credentials: {}", e))?
+        .pid();
+    let path = format!("/proc/{}/root/run/.containerenv", pid);
+    match fs::read_to_string(&path)
+        .ok()
+        .as_deref()
+        .map(containerenv_name)
+    {
+        Some(Some(name)) if name == claimed => Ok(()),
+        Some(Some(name)) => Err(format!(
+            "client says it runs in {} but {} names {}",
+            claimed, path, name
+        )),
+        _ if accept_unverified => {
+            log::warn!(
+                "Could not verify that client {} runs in {}, trusting it",
+                pid,
+                claimed
+            );
+            Ok(())
+        }
+        _ => Err(format!(
+            "could not verify that the client runs in {}, set unverified_client for it to accept it anyway",
+            claimed
+        )),
+    }
+}
+
+/// read the next message of a client, giving up after `CLIENT_TIMEOUT`
+async fn read_client_message(
+    reader: &mut (impl BufRead + Unpin),
+) -> Result<Option<ClientMessage>, io::Error> {
+    future::timeout(CLIENT_TIMEOUT, read_message(reader))
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("client sent nothing for {:?}", CLIENT_TIMEOUT),
+            )
+        })?
+}
+
+async fn handle_client(
+    iface: &InterfaceRef<ContainerDesktopEntries>,
+    stream: UnixStream,
+) -> Result<(), io::Error> {
+    let mut reader = BufReader::new(stream.clone());
+    let mut writer = stream;
+    let Some(ClientMessage::Hello {
+        min_version,
+        max_version,
+        container,
+    }) = read_client_message(&mut reader).await?
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "client did not start with Hello",
+        ));
+    };
+    let accept_unverified = iface.get().await.accepts_unverified(&container.name);
+    if let Err(reason) = verify_container(&writer, &container.name, accept_unverified) {
+        log::error!("Rejecting client in {}: {}", container.name, reason);
+        write_message(&mut writer, &ServerMessage::Rejected { reason }).await?;
+        return Ok(());
+    }
+    match negotiate(min_version, max_version) {
+        Ok(version) => {
+            log::info!(
+                "Client in {} ({}, client {}) connected with protocol version {}",
+                container.name,
+                container.os.as_deref().unwrap_or("unknown os"),
+                container.client_version,
+                version
+            );
+            write_message(&mut writer, &ServerMessage::Welcome { version }).await?;
+        }
+        Err(reason) => {
+            log::error!("Rejecting client in {}: {}", container.name, reason);
+            write_message(&mut writer, &ServerMessage::Rejected { reason }).await?;
+            return Ok(());
+        }
+    }
+    let mut bundle = Bundle::new(container);
+    loop {
+        let Some(message) = read_client_message(&mut reader).await? else {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "client hung up before Done",
+            ));
+        };
+        if !bundle.apply(message)? {
+            break;
+        }
+    }
+    let report = iface
+        .get_mut()
+        .await
+        .receive_bundle(iface.signal_context(), bundle)
+        .await;
+    write_message(&mut writer, &ServerMessage::Report(Box::new(report))).await
+}
@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
@@ -18,14 +18,25 @@ impl ContainerType {
         )
     }
 
-    pub fn format_copy(self, container_name: &str, from: &Path, to: &Path) -> String {
+    /// whether the container uses the host's home directory
+    pub fn shares_home(self) -> bool {
+        matches!(self, ContainerType::Toolbox)
+    }
+
+    pub fn format_archive(self, container_name: &str, paths: &[PathBuf]) -> String {
         match self {
             ContainerType::Toolbox => {
+                // paths are made relative to / so tar doesn't complain about stripping it
+                let paths: Vec<String> = paths
+                    .iter()
+                    .map(|path| {
+                        shell_quote(path.strip_prefix("/").unwrap_or(path).to_str().unwrap())
+                    })
+                    .collect();
                 format!(
-                    "podman container cp {}:{}/. {}/",
+                    "podman container exec {} tar -C / -chf - --ignore-failed-read {}",
                     container_name,
-                    from.to_str().unwrap(),
-                    to.to_str().unwrap()
+                    paths.join(" ")
                 )
             }
             _ => "".to_string(), // TODO: Support more container types
@@ -44,22 +55,84 @@ impl ContainerType {
     pub fn format_exec_regex_pattern(self) -> String {
         match self {
             ContainerType::Toolbox | ContainerType::Podman | ContainerType::Docker => {
-                r"(Exec=\s?)(.*)".to_string()
+                r"(?m)^[ \t]*(Exec)[ \t]*=[ \t]*([^\r\n]*)".to_string()
             }
             _ => "".to_string(),
         }
     }
 
     pub fn format_desktop_exec(self, container_name: &str) -> String {
+        match self {
+            ContainerType::Toolbox | ContainerType::Podman | ContainerType::Docker => {
+                // The launcher starts the container and forwards the session environment
+                format!(
+                    r"Exec=container-desktop-entries launch {} -- ${{2}}",
+                    container_name
+                )
+            }
+            _ => "".to_string(), // TODO: Support more container types
+        }
+    }
+
+    /// argv that runs `argv` inside the container with `env` set
+    pub fn format_launch(
+        self,
+        container_name: &str,
+        env: &[(String, String)],
+        argv: &[String],
+    ) -> Vec<String> {
+        let env = env.iter().map(|(key, value)| format!("{}={}", key, value));
+        let mut command: Vec<String> = match self {
+            ContainerType::Toolbox => ["toolbox", "run", "-c", container_name, "env"]
+                .iter()
+                .map(|arg| arg.to_string())
+                .chain(env)
+                .collect(),
+            ContainerType::Podman | ContainerType::Docker => {
+                let program = match self {
+                    ContainerType::Podman => "podman",
+                    _ => "docker",
+                };
+                let mut command = vec![
+                    program.to_string(),
+                    "container".to_string(),
+                    "exec".to_string(),
+                ];
+                for var in env {
+                    command.push("--env".to_string());
+                    command.push(var);
+                }
+                command.push(container_name.to_string());
+                command
+            }
+            _ => return Vec::new(), // TODO: Support more container types
+        };
+        command.extend(argv.iter().cloned());
+        command
+    }
+
+    /// body of a host script that runs `binary` in the container with the script's
+    /// arguments, from the directory the script was run in
+    pub fn format_shim(self, container_name: &str, binary: &str) -> String {
+        let container_name = shell_quote(container_name);
+        let binary = shell_quote(binary);
         match self {
             ContainerType::Toolbox => {
-                format!(r"Exec=toolbox run -c {} ${{2}}", container_name)
+                // toolbox run already stays in the current directory
+                format!(r#"exec toolbox run -c {} {} "$@""#, container_name, binary)
             }
-            ContainerType::Podman => {
-                // TODO: Currently not always functional
+            ContainerType::Podman | ContainerType::Docker => {
+                let program = match self {
+                    ContainerType::Podman => "podman",
+                    _ => "docker",
+                };
                 format!(
-                    r"Exec=sh -c 'podman container start {} && podman container exec {} ${{2}}'",
-                    container_name, container_name
+                    concat!(
+                        "{0} container start {1} >/dev/null || exit 1\n",
+                        "if [ -t 0 ]; then tty=-t; fi\n",
+                        r#"exec {0} container exec -i $tty -w "$PWD" {1} {2} "$@""#
+                    ),
+                    program, container_name, binary
                 )
             }
             _ => "".to_string(), // TODO: Support more container types
@@ -84,12 +157,33 @@ impl ContainerType {
         }
     }
 
+    /// command line that opens an interactive shell in the container
+    pub fn format_enter(self, container_name: &str) -> String {
+        match self {
+            ContainerType::Toolbox => {
+                format!("toolbox enter {}", container_name)
+            }
+            _ => "".to_string(), // TODO: Support more container types
+        }
+    }
+
     pub fn format_start(self, container_name: &str) -> String {
         match self {
             ContainerType::Toolbox => {
                 format!("toolbox run -c {} echo 'Started'", container_name)
             }
+            ContainerType::Podman => {
+                format!("podman container start {}", container_name)
+            }
+            ContainerType::Docker => {
+                format!("docker container start {}", container_name)
+            }
             _ => "".to_string(), // TODO: Support more container types
         }
     }
 }
+
+/// quote `s` so that `sh` reads it as a single word
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
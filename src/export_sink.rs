@@ -0,0 +1,294 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use walkdir::WalkDir;
+
+use crate::{
+    local_sink::{self, namespaced, namespaced_icon, rewrite_icon_key, HICOLOR_SIZES},
+    mime,
+    server::ClientSetupError,
+    sink::EntrySink,
+    EntryLifetime,
+};
+
+/// writes entries and icons into a tree of their own under
+/// `$XDG_DATA_HOME/container-desktop-entries`, which is added to `XDG_DATA_DIRS`
+/// with an environment.d snippet. nothing the user installed is ever touched.
+/// metainfo, mime packages, menu directories and D-Bus services are exported the
+/// same way, with one mime database built for each generation.
+///
+/// every container's files live in `containers/<name>`. changes are made to a copy
+/// in `containers/<name>.new`, which replaces it when the sync is done. `share` is a
+/// symlink to a generation that links every container's files together, and is
+/// swapped to a new generation in one rename, so the desktop never sees a half
+/// written export
+pub struct ExportSink {
+    root: PathBuf,
+    /// containers with a staged copy this run
+    staged: Mutex<HashSet<String>>,
+}
+
+impl ExportSink {
+    pub fn new() -> Result<Self, io::Error> {
+        // Nothing is written until something is exported, so a dry run can open it too
+        Ok(Self {
+            root: local_sink::data_home()?.join("container-desktop-entries"),
+            staged: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// the directory to add to `XDG_DATA_DIRS`
+    fn share(&self) -> PathBuf {
+        self.root.join("share")
+    }
+
+    fn container_dir(&self, container_name: &str) -> PathBuf {
+        self.root.join("containers").join(container_name)
+    }
+
+    fn staging_dir(&self, container_name: &str) -> PathBuf {
+        self.root
+            .join("containers")
+            .join(format!("{}.new", container_name))
+    }
+
+    /// the staged copy of a container's files, made from its current files the
+    /// first time it is needed this run
+    fn stage(&self, container_name: &str) -> Result<PathBuf, io::Error> {
+        let staging = self.staging_dir(container_name);
+        let mut staged = self.staged.lock().unwrap();
+        if !staged.contains(container_name) {
+            remove_dir_if_exists(&staging)?;
+            fs::create_dir_all(&staging)?;
+            let current = self.container_dir(container_name);
+            if current.exists() {
+                link_tree(&current, &staging)?;
+            }
+            staged.insert(container_name.to_string());
+        }
+        Ok(staging)
+    }
+
+    /// write `$XDG_CONFIG_HOME/environment.d/60-container-desktop-entries.conf`,
+    /// which appends `share` to `XDG_DATA_DIRS` from the next login on
+    fn write_environment(&self) -> Result<(), io::Error> {
+        let path = local_sink::config_home()?
+            .join("environment.d")
+            .join("60-container-desktop-entries.conf");
+        let contents = format!(
+            "XDG_DATA_DIRS=${{XDG_DATA_DIRS:-/usr/local/share:/usr/share}}:{}\n",
+            self.share().to_str().unwrap()
+        );
+        if fs::read_to_string(&path).ok().as_deref() != Some(contents.as_str()) {
+            log::info!(
+                "Writing {:?}, log in again for exported entries to show up",
+                path
+            );
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, contents)?;
+        }
+        Ok(())
+    }
+
+    /// replace every staged container with its staged copy
+    fn commit_containers(&self) -> Result<(), io::Error> {
+        for container_name in self.staged.lock().unwrap().drain() {
+            let current = self.container_dir(&container_name);
+            let staging = self.staging_dir(&container_name);
+            let old = self
+                .root
+                .join("containers")
+                .join(format!("{}.old", container_name));
+            remove_dir_if_exists(&old)?;
+            if current.exists() {
+                fs::rename(&current, &old)?;
+            }
+            if fs::read_dir(&staging)?.next().is_some() {
+                fs::rename(&staging, &current)?;
+            } else {
+                fs::remove_dir(&staging)?;
+            }
+            remove_dir_if_exists(&old)?;
+        }
+        Ok(())
+    }
+
+    /// link every container's files into a new generation, point `share` at it
+    /// and drop the previous generations
+    fn commit_generation(&self) -> Result<(), io::Error> {
+        let generations = self.root.join("generations");
+        let name = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+            .to_string();
+        let generation = generations.join(&name);
+        fs::create_dir_all(&generation)?;
+        fs::create_dir_all(self.root.join("containers"))?;
+        for entry in fs::read_dir(self.root.join("containers"))? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if !name.ends_with(".new") && !name.ends_with(".old") && path.is_dir() {
+                link_tree(&path, &generation)?;
+            }
+        }
+        let mime_dir = generation.join("mime");
+        if mime_dir.join("packages").exists() {
+            mime::update_database(&mime_dir);
+        }
+        let hicolor = generation.join("icons").join("hicolor");
+        fs::create_dir_all(&hicolor)?;
+        fs::write(hicolor.join("index.theme"), index_theme())?;
+
+        let tmp_link = self.root.join("share.tmp");
+        let _ = fs::remove_file(&tmp_link);
+        symlink(Path::new("generations").join(&name), &tmp_link)?;
+        fs::rename(&tmp_link, self.share())?;
+        log::debug!("Export now points at generation {}", name);
+
+        for entry in fs::read_dir(&generations)? {
+            let path = entry?.path();
+            if path.file_name().and_then(|n| n.to_str()) != Some(name.as_str()) {
+                fs::remove_dir_all(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `index.theme` for the exported hicolor theme, listing the directories we
+/// install icons into
+fn index_theme() -> String {
+    let mut directories: Vec<String> = HICOLOR_SIZES
+        .iter()
+        .map(|size| format!("{}x{}/apps", size, size))
+        .collect();
+    directories.push("scalable/apps".to_string());
+    let mut theme = format!(
+        "[Icon Theme]\nName=Hicolor\nComment=Fallback icon theme\nHidden=true\nDirectories={}\n",
+        directories.join(",")
+    );
+    for size in HICOLOR_SIZES.iter() {
+        theme.push_str(&format!(
+            "\n[{}x{}/apps]\nSize={}\nContext=Applications\nType=Threshold\n",
+            size, size, size
+        ));
+    }
+    theme.push_str("\n[scalable/apps]\nMinSize=1\nSize=128\nMaxSize=256\nContext=Applications\nType=Scalable\n");
+    theme
+}
+
+/// hard link every file under `from` to the same relative path under `to`
+fn link_tree(from: &Path, to: &Path) -> Result<(), io::Error> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let target = to.join(entry.path().strip_prefix(from).unwrap());
+        fs::create_dir_all(target.parent().unwrap())?;
+        fs::hard_link(entry.path(), target)?;
+    }
+    Ok(())
+}
+
+/// write `data` to `path` without touching the file it replaces, which may be
+/// linked into the current generation
+fn replace_file(path: &Path, data: &[u8]) -> Result<(), io::Error> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(tmp_path, path)
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<(), io::Error> {
+    match fs::remove_dir_all(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl EntrySink for ExportSink {
+    fn desktop_id(&self, container_name: &str, appid: &str) -> String {
+        format!("{}.desktop", namespaced(container_name, appid))
+    }
+
+    fn icon_name(&self, container_name: &str, name: &str) -> String {
+        namespaced_icon(container_name, name)
+    }
+
+    fn entry_text(&self, container_name: &str, file_text: &str) -> String {
+        rewrite_icon_key(container_name, file_text)
+    }
+
+    async fn add_entry(
+        &self,
+        container_name: &str,
+        _lifetime: EntryLifetime,
+        appid: &str,
+        file_text: &str,
+    ) -> Result<(), ClientSetupError> {
+        let path = self
+            .stage(container_name)?
+            .join("applications")
+            .join(self.desktop_id(container_name, appid));
+        replace_file(&path, self.entry_text(container_name, file_text).as_bytes())?;
+        Ok(())
+    }
+
+    async fn add_icon(
+        &self,
+        container_name: &str,
+        _lifetime: EntryLifetime,
+        name: &str,
+        data: &[u8],
+    ) -> Result<(), ClientSetupError> {
+        let path = local_sink::icon_path(
+            &self.stage(container_name)?,
+            &self.icon_name(container_name, name),
+            data,
+        );
+        replace_file(&path, data)?;
+        Ok(())
+    }
+
+    async fn remove(
+        &self,
+        container_name: &str,
+        _lifetime: EntryLifetime,
+    ) -> Result<(), ClientSetupError> {
+        // Start the container over from an empty staged copy
+        let staging = self.staging_dir(container_name);
+        remove_dir_if_exists(&staging)?;
+        fs::create_dir_all(&staging)?;
+        self.staged
+            .lock()
+            .unwrap()
+            .insert(container_name.to_string());
+        Ok(())
+    }
+
+    fn data_dir(&self, container_name: &str) -> Result<PathBuf, io::Error> {
+        self.stage(container_name)
+    }
+
+    fn mime_changed(&self, _container_name: &str) -> Result<(), io::Error> {
+        // Every container's packages share one database, built for each generation
+        Ok(())
+    }
+
+    async fn finish(&self) -> Result<(), ClientSetupError> {
+        self.write_environment()?;
+        self.commit_containers()?;
+        self.commit_generation()?;
+        Ok(())
+    }
+}
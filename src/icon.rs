@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::Cursor,
+    path::Path,
+};
+
+use image::{ImageFormat, Rgba, RgbaImage};
+
+/// size of generated placeholder icons, in pixels
+const PLACEHOLDER_SIZE: u32 = 64;
+
+/// icon name used for entries that ship no icon of their own
+pub fn placeholder_name(container_name: &str) -> String {
+    format!("container-desktop-entries-{}", container_name)
+}
+
+/// convert the contents of the icon at `path` to a format the daemon accepts (png or svg).
+/// xpm, ico and bmp icons are converted to png
+pub fn convert_icon(path: &Path, data: Vec<u8>) -> Option<Vec<u8>> {
+    let result = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png" | "svg") => return Some(data),
+        Some("xpm") => parse_xpm(&data).ok_or("invalid xpm data".to_string()),
+        Some("ico") => decode(&data, ImageFormat::Ico),
+        Some("bmp") => decode(&data, ImageFormat::Bmp),
+        _ => Err("unsupported icon format".to_string()),
+    }
+    .and_then(|img| encode_png(&img));
+    match result {
+        Ok(png) => {
+            log::debug!("Converted icon {:?} to png", path);
+            Some(png)
+        }
+        Err(e) => {
+            log::error!("Could not convert icon {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// rank an icon path: svg first, then png by size, then formats that need converting.
+/// returns `None` for formats we can't use at all
+pub fn icon_rank(path: &Path) -> Option<u32> {
+    match path.extension()?.to_str()? {
+        "svg" => Some(u32::MAX),
+        "png" => {
+            if let Some(p1) = path.parent() {
+                if let Some(p2) = p1.parent() {
+                    if let Some((a, _)) = p2.file_name()?.to_str()?.split_once('x') {
+                        if let Ok(size) = a.parse::<u32>() {
+                            return Some(size.saturating_add(1));
+                        }
+                    }
+                }
+            }
+            Some(1)
+        }
+        "xpm" | "ico" | "bmp" => Some(0),
+        _ => None,
+    }
+}
+
+/// generate a plain rounded square icon, colored by hashing `seed`
+pub fn placeholder_icon(seed: &str) -> Vec<u8> {
+    encode_png(&rounded_square(seed)).unwrap()
+}
+
+/// icon name used for a container's terminal entry
+pub fn terminal_name(container_name: &str) -> String {
+    format!("container-desktop-entries-terminal-{}", container_name)
+}
+
+/// the placeholder for `seed` with a `>_` prompt drawn on it
+pub fn terminal_icon(seed: &str) -> Vec<u8> {
+    let mut img = rounded_square(seed);
+    let white = Rgba([255, 255, 255, 255]);
+    // the prompt is laid out on a 64px grid and scaled to the icon size
+    let scale = PLACEHOLDER_SIZE as f32 / 64.0;
+    let strokes = [
+        ((16.0, 20.0), (28.0, 32.0)),
+        ((28.0, 32.0), (16.0, 44.0)),
+        ((34.0, 44.0), (48.0, 44.0)),
+    ];
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let point = (x as f32 / scale, y as f32 / scale);
+        if strokes
+            .iter()
+            .any(|(from, to)| distance_to_segment(point, *from, *to) <= 2.5)
+        {
+            *pixel = white;
+        }
+    }
+    encode_png(&img).unwrap()
+}
+
+/// a plain rounded square, colored by hashing `seed`
+fn rounded_square(seed: &str) -> RgbaImage {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    let [r, g, b, ..] = hasher.finish().to_le_bytes();
+    // keep colors away from black so the icon reads on dark panels
+    let color = Rgba([r / 2 + 64, g / 2 + 64, b / 2 + 64, 255]);
+    let radius = PLACEHOLDER_SIZE as i64 / 6;
+    let max = PLACEHOLDER_SIZE as i64 - 1;
+    RgbaImage::from_fn(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE, |x, y| {
+        // distance from the nearest corner circle center, if we are in a corner
+        let dx = (radius - x as i64).max(x as i64 - (max - radius)).max(0);
+        let dy = (radius - y as i64).max(y as i64 - (max - radius)).max(0);
+        if dx * dx + dy * dy <= radius * radius {
+            color
+        } else {
+            Rgba([0, 0, 0, 0])
+        }
+    })
+}
+
+fn distance_to_segment(point: (f32, f32), from: (f32, f32), to: (f32, f32)) -> f32 {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let t =
+        (((point.0 - from.0) * dx + (point.1 - from.1) * dy) / (dx * dx + dy * dy)).clamp(0.0, 1.0);
+    let (nx, ny) = (from.0 + t * dx, from.1 + t * dy);
+    ((point.0 - nx).powi(2) + (point.1 - ny).powi(2)).sqrt()
+}
+
+fn decode(data: &[u8], format: ImageFormat) -> Result<RgbaImage, String> {
+    image::load_from_memory_with_format(data, format)
+        .map(|img| img.to_rgba8())
+        .map_err(|e| e.to_string())
+}
+
+fn encode_png(img: &RgbaImage) -> Result<Vec<u8>, String> {
+    let mut out = Cursor::new(Vec::new());
+    img.write_to(&mut out, ImageFormat::Png)
+        .map_err(|e| e.to_string())?;
+    Ok(out.into_inner())
+}
+
+/// largest width or height of an xpm icon that is converted
+const MAX_XPM_SIZE: u32 = 1024;
+
+/// parse an XPM3 image. only the `c` (color) key of each color definition is used
+fn parse_xpm(data: &[u8]) -> Option<RgbaImage> {
+    let text = String::from_utf8_lossy(data);
+    // XPM is C source; the image is made up of the string literals in order
+    let mut strings = text.split('"').skip(1).step_by(2);
+    let mut header = strings.next()?.split_whitespace();
+    let width: u32 = header.next()?.parse().ok()?;
+    let height: u32 = header.next()?.parse().ok()?;
+    let num_colors: usize = header.next()?.parse().ok()?;
+    let cpp: usize = header.next()?.parse().ok()?;
+    if cpp == 0 || width > MAX_XPM_SIZE || height > MAX_XPM_SIZE {
+        return None;
+    }
+    // The header comes from the container, don't allocate more than the pixels it has
+    let pixel_chars = (width as usize)
+        .checked_mul(height as usize)?
+        .checked_mul(cpp)?;
+    if pixel_chars > text.len() {
+        return None;
+    }
+
+    let mut colors = HashMap::new();
+    for _ in 0..num_colors {
+        let line = strings.next()?;
+        let key = line.get(..cpp)?;
+        let mut parts = line.get(cpp..)?.split_whitespace();
+        let mut color = Rgba([0, 0, 0, 0]);
+        while let Some(kind) = parts.next() {
+            let value = parts.next()?;
+            if kind == "c" {
+                color = parse_xpm_color(value).unwrap_or(Rgba([0, 0, 0, 255]));
+                break;
+            }
+        }
+        colors.insert(key.to_string(), color);
+    }
+
+    let mut img = RgbaImage::new(width, height);
+    for y in 0..height {
+        let row = strings.next()?;
+        for x in 0..width as usize {
+            let key = row.get(x * cpp..(x + 1) * cpp)?;
+            img.put_pixel(x as u32, y, *colors.get(key)?);
+        }
+    }
+    Some(img)
+}
+
+fn parse_xpm_color(value: &str) -> Option<Rgba<u8>> {
+    if let Some(hex) = value.strip_prefix('#') {
+        // #RGB, #RRGGBB and #RRRRGGGGBBBB are all valid; keep the top byte of each channel
+        let width = hex.len() / 3;
+        if width == 0 || hex.len() % 3 != 0 {
+            return None;
+        }
+        let channel = |i: usize| {
+            let digits = hex.get(i * width..i * width + width.min(2))?;
+            let value = u8::from_str_radix(digits, 16).ok()?;
+            Some(if width == 1 { value * 17 } else { value })
+        };
+        return Some(Rgba([channel(0)?, channel(1)?, channel(2)?, 255]));
+    }
+    let rgb = match value.to_lowercase().as_str() {
+        "none" | "transparent" => return Some(Rgba([0, 0, 0, 0])),
+        "black" => [0, 0, 0],
+        "white" => [255, 255, 255],
+        "red" => [255, 0, 0],
+        "green" => [0, 255, 0],
+        "blue" => [0, 0, 255],
+        "yellow" => [255, 255, 0],
+        "cyan" => [0, 255, 255],
+        "magenta" => [255, 0, 255],
+        "gray" | "grey" => [190, 190, 190],
+        _ => return None,
+    };
+    Some(Rgba([rgb[0], rgb[1], rgb[2], 255]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xpm_colors() {
+        assert_eq!(parse_xpm_color("#f80"), Some(Rgba([255, 136, 0, 255])));
+        assert_eq!(parse_xpm_color("#FF8800"), Some(Rgba([255, 136, 0, 255])));
+        assert_eq!(
+            parse_xpm_color("#ffff88880000"),
+            Some(Rgba([255, 136, 0, 255]))
+        );
+        assert_eq!(parse_xpm_color("None"), Some(Rgba([0, 0, 0, 0])));
+        assert_eq!(parse_xpm_color("#ff88"), None);
+        assert_eq!(parse_xpm_color("chartreuse"), None);
+    }
+
+    #[test]
+    fn xpm_multi_char_pixels() {
+        let data = br##"/* XPM */
+static char *icon[] = {
+"2 2 2 2",
+"aa c #ff0000",
+"bb c None",
+"aabb",
+"bbaa"};
+"##;
+        let img = parse_xpm(data).unwrap();
+        assert_eq!(img.dimensions(), (2, 2));
+        assert_eq!(*img.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+        assert_eq!(*img.get_pixel(1, 0), Rgba([0, 0, 0, 0]));
+        assert_eq!(*img.get_pixel(0, 1), Rgba([0, 0, 0, 0]));
+        assert_eq!(*img.get_pixel(1, 1), Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn xpm_truncated() {
+        let data = br##"static char *icon[] = {
+"2 2 1 1",
+". c #000",
+".."
+"##;
+        assert!(parse_xpm(data).is_none());
+    }
+
+    #[test]
+    fn xpm_oversized_header() {
+        assert!(parse_xpm(br##""100000 100000 1 1", ". c #000", "."##).is_none());
+        assert!(parse_xpm(br##""1000 1000 1 1", ". c #000", "."##).is_none());
+    }
+}
@@ -0,0 +1,106 @@
+use std::{collections::HashMap, env, io, os::unix::process::CommandExt, process::Command};
+
+use zbus::{zvariant::Value, Connection};
+
+use crate::{server, server::ClientSetupError, ContainerList};
+
+/// session environment passed on to apps in the container, when set on the host
+const FORWARDED_ENV: [&str; 10] = [
+    "DISPLAY",
+    "WAYLAND_DISPLAY",
+    "XAUTHORITY",
+    "XDG_RUNTIME_DIR",
+    "XDG_SESSION_TYPE",
+    "XDG_CURRENT_DESKTOP",
+    "DBUS_SESSION_BUS_ADDRESS",
+    "PULSE_SERVER",
+    "LANG",
+    "DESKTOP_STARTUP_ID",
+];
+
+/// start `container_name` if needed and replace this process with `argv` running
+/// inside it. this is what the `Exec=` of exported entries runs. failures are
+/// shown as a desktop notification, since there is no terminal to print them to
+pub async fn launch(
+    containers: &ContainerList,
+    container_name: &str,
+    argv: &[String],
+) -> Result<(), ClientSetupError> {
+    let result = try_launch(containers, container_name, argv).await;
+    if let Err(e) = result.as_ref() {
+        log::error!("Could not launch {:?} in {}: {}", argv, container_name, e);
+        notify(
+            &format!("Could not start {}", container_name),
+            &format!("{}: {}", argv.join(" "), e),
+        )
+        .await;
+    }
+    result
+}
+
+async fn try_launch(
+    containers: &ContainerList,
+    container_name: &str,
+    argv: &[String],
+) -> Result<(), ClientSetupError> {
+    let container_type = containers
+        .containers
+        .iter()
+        .find(|(name, _)| name == container_name)
+        .map(|(_, container_type)| *container_type)
+        .ok_or(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not a configured container", container_name),
+        ))?;
+    server::start_client(
+        container_name,
+        container_type,
+        &containers.settings(container_name),
+    )
+    .await?;
+    let env: Vec<(String, String)> = FORWARDED_ENV
+        .iter()
+        .filter_map(|key| Some((key.to_string(), env::var(key).ok()?)))
+        .collect();
+    let command = container_type.format_launch(container_name, &env, argv);
+    let Some((program, args)) = command.split_first() else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot launch apps in {:?} containers", container_type),
+        )
+        .into());
+    };
+    log::debug!("Launching {:?}", command);
+    // Only returns if the program could not be run
+    Err(Command::new(program).args(args).exec().into())
+}
+
+/// show a desktop notification, logging instead if there is no notification server
+async fn notify(summary: &str, body: &str) {
+    let result = async {
+        let connection = Connection::session().await?;
+        connection
+            .call_method(
+                Some("org.freedesktop.Notifications"),
+                "/org/freedesktop/Notifications",
+                Some("org.freedesktop.Notifications"),
+                "Notify",
+                &(
+                    "container-desktop-entries",
+                    0u32,
+                    "dialog-error",
+                    summary,
+                    body,
+                    Vec::<&str>::new(),
+                    HashMap::<&str, Value>::new(),
+                    -1i32,
+                ),
+            )
+            .await?;
+        Ok::<(), zbus::Error>(())
+    }
+    .await;
+    if let Err(e) = result {
+        log::warn!("could not show notification: {}", e);
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+use image::GenericImageView;
+use regex::Regex;
+
+use crate::{manifest::FileManifest, server::ClientSetupError, sink::EntrySink, EntryLifetime};
+
+/// icon sizes hicolor has directories for
+pub const HICOLOR_SIZES: [u32; 10] = [16, 22, 24, 32, 48, 64, 96, 128, 256, 512];
+
+/// writes entries and icons straight into `$XDG_DATA_HOME`, for hosts without
+/// desktop-entry-daemon. every file name is prefixed with the container name so
+/// nothing the user installed is overwritten, and every file written is tracked in
+/// a manifest so it can be cleaned up. lifetimes are ignored; files stay until the
+/// container's entries are removed
+pub struct LocalSink {
+    data_home: PathBuf,
+    manifest: Mutex<FileManifest>,
+}
+
+impl LocalSink {
+    pub fn new() -> Result<Self, io::Error> {
+        Ok(Self {
+            data_home: data_home()?,
+            manifest: Mutex::new(FileManifest::load("local")?),
+        })
+    }
+
+    fn write(&self, container_name: &str, path: &Path, data: &[u8]) -> Result<(), io::Error> {
+        let mut manifest = self.manifest.lock().unwrap();
+        manifest.write(container_name, path, data)?;
+        manifest.save()
+    }
+}
+
+/// `$XDG_DATA_HOME`, usually `~/.local/share`
+pub fn data_home() -> Result<PathBuf, io::Error> {
+    let data_home = env::var("XDG_DATA_HOME")
+        .or(env::var("HOME").map(|home| format!("{}/.local/share", home)))
+        .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+    Ok(PathBuf::from(data_home))
+}
+
+/// `$XDG_DATA_DIRS` of the host, `/usr/local/share:/usr/share` if unset. the dirs
+/// we export into ourselves are left out
+pub fn data_dirs() -> Vec<PathBuf> {
+    let data_dirs = env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or("/usr/local/share:/usr/share".to_string());
+    let ours = data_home().map(|dir| dir.join("container-desktop-entries"));
+    env::split_paths(&data_dirs)
+        .filter(|dir| !ours.as_ref().is_ok_and(|ours| dir.starts_with(ours)))
+        .collect()
+}
+
+/// `$XDG_CONFIG_HOME`, usually `~/.config`
+pub fn config_home() -> Result<PathBuf, io::Error> {
+    let config_home = env::var("XDG_CONFIG_HOME")
+        .or(env::var("HOME").map(|home| format!("{}/.config", home)))
+        .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+    Ok(PathBuf::from(config_home))
+}
+
+/// name a container's file is installed under on the host
+pub fn namespaced(container_name: &str, name: &str) -> String {
+    format!("container-desktop-entries-{}-{}", container_name, name)
+}
+
+/// name an icon is installed under on the host. `Icon=` may be an absolute path,
+/// in which case the file name is used
+pub fn namespaced_icon(container_name: &str, icon_name: &str) -> String {
+    let name = Path::new(icon_name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(icon_name);
+    namespaced(container_name, name)
+}
+
+/// point the `Icon=` key of `file_text` at the namespaced icon
+pub fn rewrite_icon_key(container_name: &str, file_text: &str) -> String {
+    let icon_regex = Regex::new(r"(?m)^(Icon\s*=\s*)(.*)$").unwrap();
+    icon_regex
+        .replace_all(file_text, |caps: &regex::Captures| {
+            format!("{}{}", &caps[1], namespaced_icon(container_name, &caps[2]))
+        })
+        .to_string()
+}
+
+/// where in the hicolor theme an icon belongs, based on its format and size
+pub fn icon_path(base: &Path, name: &str, data: &[u8]) -> PathBuf {
+    let hicolor = base.join("icons").join("hicolor");
+    match image::load_from_memory(data) {
+        Ok(img) => {
+            let (width, _) = img.dimensions();
+            let size = HICOLOR_SIZES
+                .iter()
+                .min_by_key(|size| size.abs_diff(width))
+                .unwrap();
+            hicolor
+                .join(format!("{}x{}", size, size))
+                .join("apps")
+                .join(format!("{}.png", name))
+        }
+        // Anything we can't decode as a raster image is svg
+        Err(_) => hicolor
+            .join("scalable")
+            .join("apps")
+            .join(format!("{}.svg", name)),
+    }
+}
+
+#[async_trait]
+impl EntrySink for LocalSink {
+    fn desktop_id(&self, container_name: &str, appid: &str) -> String {
+        format!("{}.desktop", namespaced(container_name, appid))
+    }
+
+    fn icon_name(&self, container_name: &str, name: &str) -> String {
+        namespaced_icon(container_name, name)
+    }
+
+    fn entry_text(&self, container_name: &str, file_text: &str) -> String {
+        rewrite_icon_key(container_name, file_text)
+    }
+
+    async fn add_entry(
+        &self,
+        container_name: &str,
+        _lifetime: EntryLifetime,
+        appid: &str,
+        file_text: &str,
+    ) -> Result<(), ClientSetupError> {
+        let path = self
+            .data_home
+            .join("applications")
+            .join(self.desktop_id(container_name, appid));
+        let file_text = self.entry_text(container_name, file_text);
+        self.write(container_name, &path, file_text.as_bytes())?;
+        Ok(())
+    }
+
+    async fn add_icon(
+        &self,
+        container_name: &str,
+        _lifetime: EntryLifetime,
+        name: &str,
+        data: &[u8],
+    ) -> Result<(), ClientSetupError> {
+        let path = icon_path(&self.data_home, &self.icon_name(container_name, name), data);
+        self.write(container_name, &path, data)?;
+        Ok(())
+    }
+
+    async fn remove(
+        &self,
+        container_name: &str,
+        _lifetime: EntryLifetime,
+    ) -> Result<(), ClientSetupError> {
+        let mut manifest = self.manifest.lock().unwrap();
+        manifest.remove_container(container_name);
+        manifest.save()?;
+        Ok(())
+    }
+}
@@ -1,31 +1,191 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use container_type::ContainerType;
 use ron::de::SpannedError;
 use serde::{Deserialize, Serialize};
 use server::ClientSetupError;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Display;
 use std::{env, fs, io};
 
-use std::{fs::read_to_string, path::Path};
+use std::{
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
 
+mod activation;
+mod autostart;
+mod cache;
+mod cli;
+mod client;
 mod container_type;
 mod desktop_entry;
+mod export_sink;
+mod icon;
+mod launch;
+mod local_sink;
+mod manifest;
+mod menu;
+mod metainfo;
+mod mime;
+mod protocol;
+mod search_provider;
 mod server;
+mod service;
+mod shims;
+mod sink;
 
 /// program to get desktop entries from containers
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    #[arg(short, long, requires = "server", value_name = "CONFIG_PATH")]
-    /// [AS SERVER] Path to an alternate config for the program.
+    #[arg(short, long, global = true, value_name = "CONFIG_PATH")]
+    /// Path to an alternate config for the program.
     /// Default is $HOME/.config/container-desktop-entries/containers.ron
     config: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Sync the given containers now, or every container if none are given
+    Sync {
+        containers: Vec<String>,
+        /// Print the rewritten entries, their appids and chosen icons instead of registering them
+        #[arg(long)]
+        dry_run: bool,
+        /// With --dry-run, write the entries and icons to this directory instead of printing them
+        #[arg(long, requires = "dry_run", value_name = "DIR")]
+        output: Option<PathBuf>,
+        /// Print a report of what was exported, skipped and missing instead of a summary
+        #[arg(long, value_enum, value_name = "FORMAT")]
+        report: Option<ReportFormat>,
+    },
+    /// List the configured containers and the apps exported from them
+    List,
+    /// Remove the entries of a container until it is synced again
+    Remove { container: String },
+    /// Show when each container was last synced and whether it worked
+    Status,
+    /// Sync every container and keep running, serving the D-Bus interface (default)
+    Daemon,
+    /// [IN A CONTAINER] Send this container's entries and icons to the host
+    Client {
+        /// Name of this container. Read from /run/.containerenv by default
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Start a container if needed and run a command in it. Used by exported entries
+    Launch {
+        container: String,
+        #[arg(last = true, required = true)]
+        argv: Vec<String>,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum ReportFormat {
+    Json,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct ContainerList {
     pub containers: Vec<(String, ContainerType)>,
+    /// how many containers are synced at the same time
+    #[serde(default = "default_max_concurrent")]
+    pub max_concurrent: usize,
+    /// per container options, keyed by container name
+    #[serde(default)]
+    pub settings: HashMap<String, ContainerSettings>,
+    /// where entries and icons are written to
+    #[serde(default)]
+    pub backend: Backend,
+    /// command that runs a command line in a terminal, e.g. `gnome-terminal --`.
+    /// terminal entries are left to the desktop's default terminal if not set
+    #[serde(default)]
+    pub terminal_command: Option<String>,
+    /// where command-line shims are written, `~/.local/bin` if not set
+    #[serde(default)]
+    pub bin_dir: Option<PathBuf>,
+}
+
+fn default_max_concurrent() -> usize {
+    4
+}
+
+impl ContainerList {
+    /// options for `container_name`, or the defaults if it has none
+    pub fn settings(&self, container_name: &str) -> ContainerSettings {
+        self.settings
+            .get(container_name)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+struct ContainerSettings {
+    /// seconds a single command in the container may run before it is killed
+    pub timeout: u64,
+    /// how many more times to try starting the container if it doesn't become ready
+    pub retries: u32,
+    /// seconds to wait before the first retry, doubled for every retry after it
+    pub backoff: u64,
+    /// how long the daemon keeps this container's entries around
+    pub lifetime: EntryLifetime,
+    /// add an entry that opens a terminal in this container
+    pub terminal: bool,
+    /// commands in the container to make callable from the host shell
+    pub binaries: Vec<String>,
+    /// default application for mime types, by desktop file id in the container
+    pub mime_defaults: HashMap<String, String>,
+    /// start the container's autostart entries with the host session
+    pub autostart: bool,
+    /// accept entries from a client whose container the host can't look into to
+    /// check its name, e.g. a rootful docker container
+    pub unverified_client: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+enum Backend {
+    /// desktop-entry-daemon if it is on the session bus, local files otherwise
+    #[default]
+    Auto,
+    /// register entries with desktop-entry-daemon
+    Daemon,
+    /// write entries and icons into `$XDG_DATA_HOME`
+    Local,
+    /// write entries and icons into a separate data dir that is added to `XDG_DATA_DIRS`
+    Export,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+enum EntryLifetime {
+    /// entries are removed when the session ends, and pushed again every login
+    #[default]
+    Session,
+    /// entries stay registered across sessions until the container is removed
+    Persistent,
+    /// entries are removed when this program exits
+    Process,
+}
+
+impl Default for ContainerSettings {
+    fn default() -> Self {
+        Self {
+            timeout: 60,
+            retries: 3,
+            backoff: 2,
+            lifetime: EntryLifetime::Session,
+            terminal: false,
+            binaries: Vec::new(),
+            mime_defaults: HashMap::new(),
+            autostart: false,
+            unverified_client: false,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -96,6 +256,12 @@ async fn main() -> Result<(), CDEError> {
 
     let args = Args::parse();
 
+    // The client runs inside the container, where there is no config
+    if let Some(Command::Client { name }) = args.command {
+        client::client(name).await?;
+        return Ok(());
+    }
+
     let default_path_str = format!(
         "{}/.config/container-desktop-entries/containers.ron",
         env::var("HOME")?
@@ -111,7 +277,7 @@ async fn main() -> Result<(), CDEError> {
                 conf_path
             );
             let _ = fs::create_dir(conf_path.parent().unwrap());
-            let _ = fs::write(conf_path,"// Example config:\n/*\n(\n  containers:\n  [\n    (\"fedora-toolbox-40\", Toolbox),\n    (\"docker-container\", Docker),\n  ],\n)\n*/",
+            let _ = fs::write(conf_path,"// Example config:\n/*\n(\n  containers:\n  [\n    (\"fedora-toolbox-40\", Toolbox),\n    (\"docker-container\", Docker),\n  ],\n  max_concurrent: 4,\n  backend: Auto,\n  settings: {\n    \"fedora-toolbox-40\": (timeout: 60, retries: 3, backoff: 2, lifetime: Session, terminal: true, binaries: [\"gcc\"]),\n  },\n)\n*/",
             );
             log::info!(
                 "write a configuration file. an example has been written to the config directory"
@@ -121,7 +287,26 @@ async fn main() -> Result<(), CDEError> {
     }
     let config_data: ContainerList = ron::from_str(&read_to_string(conf_path)?)?;
 
-    server::server(config_data, "container-desktop-entries").await?;
+    let owner = "container-desktop-entries";
+    match args.command.unwrap_or(Command::Daemon) {
+        Command::Sync {
+            containers,
+            dry_run: true,
+            output,
+            report,
+        } => cli::dry_run(config_data, containers, owner, output, report).await?,
+        Command::Sync {
+            containers, report, ..
+        } => cli::sync(config_data, containers, owner, report).await?,
+        Command::List => cli::list(&config_data),
+        Command::Remove { container } => cli::remove(config_data, container, owner).await?,
+        Command::Status => cli::status(&config_data).await,
+        Command::Daemon => server::server(config_data, owner).await?,
+        Command::Launch { container, argv } => {
+            launch::launch(&config_data, &container, &argv).await?
+        }
+        Command::Client { .. } => unreachable!(),
+    }
 
     Ok(())
 }
@@ -0,0 +1,93 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::state_dir;
+
+/// files written to the host on behalf of containers, so they can be removed
+/// again when a container goes away. kept in the state directory
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct FileManifest {
+    #[serde(skip)]
+    path: PathBuf,
+    containers: HashMap<String, BTreeSet<PathBuf>>,
+}
+
+impl FileManifest {
+    /// load the manifest called `name`, or an empty one if it doesn't exist yet
+    pub fn load(name: &str) -> Result<Self, io::Error> {
+        let path = state_dir()
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no state directory",
+            ))?
+            .join(format!("{}.json", name));
+        let mut manifest: Self = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e),
+        };
+        manifest.path = path;
+        Ok(manifest)
+    }
+
+    pub fn save(&self) -> Result<(), io::Error> {
+        fs::create_dir_all(self.path.parent().unwrap())?;
+        fs::write(&self.path, serde_json::to_vec(self)?)
+    }
+
+    /// write `data` to `path` and record it as belonging to `container_name`
+    pub fn write(
+        &mut self,
+        container_name: &str,
+        path: &Path,
+        data: &[u8],
+    ) -> Result<(), io::Error> {
+        fs::create_dir_all(path.parent().unwrap())?;
+        // Replace the file instead of writing into it, it may be linked elsewhere
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(tmp_path, path)?;
+        self.containers
+            .entry(container_name.to_string())
+            .or_default()
+            .insert(path.to_path_buf());
+        Ok(())
+    }
+
+    /// every file recorded for `container_name`
+    pub fn files(&self, container_name: &str) -> BTreeSet<PathBuf> {
+        self.containers
+            .get(container_name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// names of every container with files recorded
+    pub fn container_names(&self) -> Vec<String> {
+        self.containers.keys().cloned().collect()
+    }
+
+    /// delete every file recorded for `container_name`. files that can't be
+    /// deleted stay recorded so removing them is tried again next time
+    pub fn remove_container(&mut self, container_name: &str) {
+        let mut failed = BTreeSet::new();
+        for path in self.containers.remove(container_name).unwrap_or_default() {
+            match fs::remove_file(&path) {
+                Ok(_) => log::debug!("Removed {:?}", path),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    log::error!("Could not remove {:?}: {}", path, e);
+                    failed.insert(path);
+                }
+            }
+        }
+        if !failed.is_empty() {
+            self.containers.insert(container_name.to_string(), failed);
+        }
+    }
+}
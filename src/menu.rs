@@ -0,0 +1,94 @@
+use std::io;
+
+use regex::Regex;
+
+use crate::{
+    local_sink::{config_home, namespaced},
+    manifest::FileManifest,
+    sink::EntrySink,
+};
+
+/// category every entry of `container_name` is put in
+pub fn category(container_name: &str) -> String {
+    format!("X-Container-{}", container_name)
+}
+
+/// add the container's category to the `Categories=` key of `file_text`
+pub fn add_category(container_name: &str, file_text: &str) -> String {
+    let category = category(container_name);
+    let categories_regex = Regex::new(r"(?m)^(Categories\s*=\s*)(.*?)\s*$").unwrap();
+    if categories_regex.is_match(file_text) {
+        categories_regex
+            .replace(file_text, |caps: &regex::Captures| {
+                let existing = caps[2].trim_end_matches(';');
+                if existing.is_empty() {
+                    format!("{}{};", &caps[1], category)
+                } else {
+                    format!("{}{};{};", &caps[1], existing, category)
+                }
+            })
+            .to_string()
+    } else {
+        file_text.replacen(
+            "[Desktop Entry]",
+            &format!("[Desktop Entry]\nCategories={};", category),
+            1,
+        )
+    }
+}
+
+/// write a menu merge file and a directory entry that give `container_name` a
+/// submenu of its own in menus that follow the XDG menu spec. the directory entry
+/// goes into the sink's data dir
+pub fn export_menu(sink: &dyn EntrySink, container_name: &str) -> Result<(), io::Error> {
+    let name = namespaced(container_name, "menu");
+    let directory_path = sink
+        .data_dir(container_name)?
+        .join("desktop-directories")
+        .join(format!("{}.directory", name));
+    let menu_path = config_home()?
+        .join("menus")
+        .join("applications-merged")
+        .join(format!("{}.menu", name));
+    let directory = format!(
+        "[Desktop Entry]\nType=Directory\nName={0}\nComment=Apps in {0}\n",
+        container_name
+    );
+    let menu = format!(
+        concat!(
+            "<!DOCTYPE Menu PUBLIC \"-//freedesktop//DTD Menu 1.0//EN\"\n",
+            " \"http://www.freedesktop.org/standards/menu-spec/1.0/menu.dtd\">\n",
+            "<Menu>\n",
+            "  <Name>Applications</Name>\n",
+            "  <Menu>\n",
+            "    <Name>{0}</Name>\n",
+            "    <Directory>{1}.directory</Directory>\n",
+            "    <Include>\n",
+            "      <Category>{2}</Category>\n",
+            "    </Include>\n",
+            "  </Menu>\n",
+            "</Menu>\n"
+        ),
+        xml_escape(container_name),
+        xml_escape(&name),
+        xml_escape(&category(container_name))
+    );
+    let mut manifest = FileManifest::load("menus")?;
+    manifest.remove_container(container_name);
+    manifest.write(container_name, &directory_path, directory.as_bytes())?;
+    manifest.write(container_name, &menu_path, menu.as_bytes())?;
+    manifest.save()
+}
+
+/// remove the submenu of `container_name`
+pub fn remove_menu(container_name: &str) -> Result<(), io::Error> {
+    let mut manifest = FileManifest::load("menus")?;
+    manifest.remove_container(container_name);
+    manifest.save()
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,63 @@
+use std::{io, path::Path};
+
+use regex::Regex;
+
+use crate::{
+    local_sink::namespaced, manifest::FileManifest, protocol::MetainfoFile, sink::EntrySink,
+};
+
+/// appid a metainfo file describes, from names like `org.gnome.Evince.metainfo.xml`
+pub fn appid(path: &Path) -> Option<&str> {
+    let file_name = path.file_name()?.to_str()?;
+    file_name
+        .strip_suffix(".metainfo.xml")
+        .or(file_name.strip_suffix(".appdata.xml"))
+}
+
+/// publish the metainfo of `container_name` into the sink's data dir, replacing
+/// what was published before. launchables are pointed at the entries as they are
+/// installed on the host
+pub fn export_metainfo(
+    sink: &dyn EntrySink,
+    container_name: &str,
+    files: &[MetainfoFile],
+) -> Result<(), io::Error> {
+    let metainfo_dir = sink.data_dir(container_name)?.join("metainfo");
+    let launchable_regex = Regex::new(
+        r#"(<launchable\s+type\s*=\s*["']desktop-id["']\s*>)\s*([^<]*?)\s*(</launchable>)"#,
+    )
+    .unwrap();
+    let mut manifest = FileManifest::load("metainfo")?;
+    manifest.remove_container(container_name);
+    for file in files.iter() {
+        let Some(appid) = appid(&file.path) else {
+            continue;
+        };
+        let Ok(text) = std::str::from_utf8(&file.data) else {
+            log::warn!("Ignoring metainfo {:?}, not valid utf-8", file.path);
+            continue;
+        };
+        let text = launchable_regex.replace_all(text, |caps: &regex::Captures| {
+            let desktop_id = caps[2].strip_suffix(".desktop").unwrap_or(&caps[2]);
+            format!(
+                "{}{}{}",
+                &caps[1],
+                sink.desktop_id(container_name, desktop_id),
+                &caps[3]
+            )
+        });
+        let path = metainfo_dir.join(format!(
+            "{}.metainfo.xml",
+            namespaced(container_name, appid)
+        ));
+        manifest.write(container_name, &path, text.as_bytes())?;
+    }
+    manifest.save()
+}
+
+/// remove the metainfo published for `container_name`
+pub fn remove_metainfo(container_name: &str) -> Result<(), io::Error> {
+    let mut manifest = FileManifest::load("metainfo")?;
+    manifest.remove_container(container_name);
+    manifest.save()
+}
@@ -0,0 +1,376 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    cache::MimeDefaults,
+    local_sink::{config_home, data_dirs, namespaced},
+    manifest::FileManifest,
+    protocol::MimePackage,
+    sink::EntrySink,
+};
+
+/// group of mimeapps.list that holds default applications
+const DEFAULTS_GROUP: &str = "[Default Applications]";
+
+/// the package of shared-mime-info with every common type. installing the container's
+/// copy would override the host's, which may well be newer
+const SHARED_MIME_INFO: &str = "freedesktop.org.xml";
+
+/// install the mime packages of `container_name` into the sink's data dir, replacing
+/// the ones installed before, and set its default applications. `defaults` maps
+/// mime types to desktop file ids in the container
+pub fn export_mime(
+    sink: &dyn EntrySink,
+    container_name: &str,
+    packages: &[MimePackage],
+    defaults: &HashMap<String, String>,
+) -> Result<(), io::Error> {
+    let mime_dir = sink.data_dir(container_name)?.join("mime");
+    let mut manifest = FileManifest::load("mime")?;
+    let host_packages = host_packages();
+    let files: BTreeMap<PathBuf, &[u8]> = packages
+        .iter()
+        .filter_map(|package| {
+            let file_name = package.path.file_name()?.to_str()?;
+            if !is_container_only(file_name, &host_packages) {
+                log::debug!("Skipping {:?}, the host has it too", package.path);
+                return None;
+            }
+            Some((
+                mime_dir
+                    .join("packages")
+                    .join(namespaced(container_name, file_name)),
+                package.data.as_slice(),
+            ))
+        })
+        .collect();
+    // Updating the database is slow, so only do it when something changed
+    let unchanged = manifest.files(container_name).iter().eq(files.keys())
+        && files
+            .iter()
+            .all(|(path, data)| fs::read(path).is_ok_and(|old| old == *data));
+    if !unchanged {
+        manifest.remove_container(container_name);
+        for (path, data) in files.iter() {
+            manifest.write(container_name, path, data)?;
+        }
+        manifest.save()?;
+        log::info!(
+            "{}: installed {} mime packages",
+            container_name,
+            files.len()
+        );
+        sink.mime_changed(container_name)?;
+    }
+
+    let defaults: BTreeMap<String, String> = defaults
+        .iter()
+        .map(|(mime_type, desktop_id)| {
+            let appid = desktop_id.strip_suffix(".desktop").unwrap_or(desktop_id);
+            (mime_type.clone(), sink.desktop_id(container_name, appid))
+        })
+        .collect();
+    let mut state = MimeDefaults::load();
+    if state
+        .containers
+        .get(container_name)
+        .cloned()
+        .unwrap_or_default()
+        != defaults
+    {
+        let previous = state.containers.remove(container_name).unwrap_or_default();
+        let set = edit_mimeapps(|group| set_defaults(group, &previous, defaults))?;
+        if !set.is_empty() {
+            state.containers.insert(container_name.to_string(), set);
+        }
+        state.save()?;
+    }
+    Ok(())
+}
+
+/// file names of the mime packages in the host's `XDG_DATA_DIRS`
+fn host_packages() -> HashSet<String> {
+    data_dirs()
+        .iter()
+        .filter_map(|dir| fs::read_dir(dir.join("mime").join("packages")).ok())
+        .flat_map(|read_dir| read_dir.flatten())
+        .filter_map(|dir_entry| dir_entry.file_name().into_string().ok())
+        .collect()
+}
+
+/// whether a container's mime package named `file_name` adds anything the host lacks
+fn is_container_only(file_name: &str, host_packages: &HashSet<String>) -> bool {
+    file_name.ends_with(".xml")
+        && file_name != SHARED_MIME_INFO
+        && !host_packages.contains(file_name)
+}
+
+/// remove the mime packages and default applications of `container_name`
+pub fn remove_mime(sink: &dyn EntrySink, container_name: &str) -> Result<(), io::Error> {
+    let mut manifest = FileManifest::load("mime")?;
+    if !manifest.files(container_name).is_empty() {
+        manifest.remove_container(container_name);
+        manifest.save()?;
+        sink.mime_changed(container_name)?;
+    }
+    let mut state = MimeDefaults::load();
+    if let Some(previous) = state.containers.remove(container_name) {
+        edit_mimeapps(|group| unset_defaults(group, &previous))?;
+        state.save()?;
+    }
+    Ok(())
+}
+
+/// replace the `previous` defaults of a container in `group` with `defaults`, never
+/// overriding one the user chose. returns the defaults that were set
+fn set_defaults(
+    group: &mut Vec<(String, String)>,
+    previous: &BTreeMap<String, String>,
+    defaults: BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    unset_defaults(group, previous);
+    let mut set = BTreeMap::new();
+    for (mime_type, desktop_id) in defaults {
+        if !group.iter().any(|(key, _)| *key == mime_type) {
+            group.push((mime_type.clone(), desktop_id.clone()));
+            set.insert(mime_type, desktop_id);
+        }
+    }
+    set
+}
+
+/// remove the `previous` defaults of a container from `group`, unless the user
+/// has changed them since
+fn unset_defaults(group: &mut Vec<(String, String)>, previous: &BTreeMap<String, String>) {
+    group.retain(|(key, value)| previous.get(key) != Some(value));
+}
+
+pub fn update_database(mime_dir: &Path) {
+    match Command::new("update-mime-database").arg(mime_dir).status() {
+        Ok(status) if status.success() => log::debug!("Updated {:?}", mime_dir),
+        Ok(status) => log::error!("update-mime-database exited with {}", status),
+        Err(e) => log::error!("Could not run update-mime-database: {}", e),
+    }
+}
+
+/// let `edit` change the default applications in `$XDG_CONFIG_HOME/mimeapps.list`.
+/// everything else in the file is kept as is
+fn edit_mimeapps<T>(edit: impl FnOnce(&mut Vec<(String, String)>) -> T) -> Result<T, io::Error> {
+    let path = config_home()?.join("mimeapps.list");
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let (new_text, result) = edit_defaults(&text, edit);
+    if new_text != text {
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, new_text)?;
+    }
+    Ok(result)
+}
+
+/// the key and value of a `key=value` line of a mimeapps.list
+fn parse_key(line: &str) -> Option<(String, String)> {
+    if line.trim_start().starts_with('#') {
+        return None;
+    }
+    let (key, value) = line.split_once('=')?;
+    Some((key.trim().to_string(), value.trim().to_string()))
+}
+
+/// let `edit` change the default applications group of the mimeapps.list `text`.
+/// keys are changed in place and new ones added after the existing ones, so undoing
+/// an edit gives back the same text
+fn edit_defaults<T>(text: &str, edit: impl FnOnce(&mut Vec<(String, String)>) -> T) -> (String, T) {
+    let lines: Vec<&str> = text.lines().collect();
+    let header = lines.iter().position(|line| line.trim() == DEFAULTS_GROUP);
+    let (start, end) = match header {
+        Some(header) => {
+            let end = lines[header + 1..]
+                .iter()
+                .position(|line| line.trim_start().starts_with('['))
+                .map_or(lines.len(), |len| header + 1 + len);
+            (header + 1, end)
+        }
+        None => (lines.len(), lines.len()),
+    };
+    let original: Vec<(String, String)> = lines[start..end]
+        .iter()
+        .filter_map(|line| parse_key(line))
+        .collect();
+    let mut group = original.clone();
+    let result = edit(&mut group);
+    if group == original {
+        return (text.to_string(), result);
+    }
+
+    let mut section: Vec<String> = Vec::new();
+    let mut written = HashSet::new();
+    let mut insert_at = None;
+    for line in lines[start..end].iter() {
+        let Some((key, value)) = parse_key(line) else {
+            section.push(line.to_string());
+            continue;
+        };
+        if let Some((_, new_value)) = group.iter().find(|(other, _)| *other == key) {
+            if written.insert(key.clone()) {
+                section.push(if *new_value == value {
+                    line.to_string()
+                } else {
+                    format!("{}={}", key, new_value)
+                });
+            }
+        }
+        insert_at = Some(section.len());
+    }
+    let insert_at = insert_at.unwrap_or_else(|| {
+        section.len()
+            - section
+                .iter()
+                .rev()
+                .take_while(|line| line.trim().is_empty())
+                .count()
+    });
+    let added: Vec<String> = group
+        .iter()
+        .filter(|(key, _)| !original.iter().any(|(other, _)| other == key))
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    section.splice(insert_at..insert_at, added);
+
+    let mut out: Vec<String> = Vec::new();
+    match header {
+        // Drop a group that ends up empty along with the blank line before it
+        Some(header) if section.iter().all(|line| line.trim().is_empty()) => {
+            let before = match header.checked_sub(1) {
+                Some(blank) if lines[blank].trim().is_empty() => blank,
+                _ => header,
+            };
+            out.extend(lines[..before].iter().map(|line| line.to_string()));
+        }
+        Some(header) => {
+            out.extend(lines[..=header].iter().map(|line| line.to_string()));
+            out.extend(section);
+        }
+        None => {
+            out.extend(lines.iter().map(|line| line.to_string()));
+            if out.last().is_some_and(|line| !line.trim().is_empty()) {
+                out.push(String::new());
+            }
+            out.push(DEFAULTS_GROUP.to_string());
+            out.extend(section);
+        }
+    }
+    out.extend(lines[end..].iter().map(|line| line.to_string()));
+    if out.is_empty() {
+        return (String::new(), result);
+    }
+    (out.join("\n") + "\n", result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn set(text: &str, pairs: &[(&str, &str)]) -> String {
+        edit_defaults(text, |group| {
+            set_defaults(group, &BTreeMap::new(), defaults(pairs))
+        })
+        .0
+    }
+
+    fn unset(text: &str, pairs: &[(&str, &str)]) -> String {
+        edit_defaults(text, |group| unset_defaults(group, &defaults(pairs))).0
+    }
+
+    #[test]
+    fn skips_packages_the_host_has() {
+        let host: HashSet<String> = ["gnome-shell.xml".to_string()].into();
+        assert!(is_container_only("org.example.Foo.xml", &host));
+        assert!(!is_container_only("freedesktop.org.xml", &host));
+        assert!(!is_container_only("gnome-shell.xml", &host));
+        assert!(!is_container_only("README", &host));
+    }
+
+    #[test]
+    fn adds_missing_group() {
+        let text = "[Added Associations]\ntext/plain=a.desktop;\n";
+        let edited = set(text, &[("text/x-foo", "box-foo.desktop")]);
+        assert_eq!(
+            edited,
+            "[Added Associations]\ntext/plain=a.desktop;\n\n[Default Applications]\ntext/x-foo=box-foo.desktop\n"
+        );
+        assert_eq!(unset(&edited, &[("text/x-foo", "box-foo.desktop")]), text);
+        assert_eq!(set("", &[]), "");
+    }
+
+    #[test]
+    fn edits_group_followed_by_another() {
+        let text = "[Default Applications]\ntext/plain=a.desktop\n\n[Added Associations]\ntext/x-foo=b.desktop;\n";
+        let edited = set(text, &[("text/x-foo", "box-foo.desktop")]);
+        assert_eq!(
+            edited,
+            "[Default Applications]\ntext/plain=a.desktop\ntext/x-foo=box-foo.desktop\n\n[Added Associations]\ntext/x-foo=b.desktop;\n"
+        );
+        assert_eq!(unset(&edited, &[("text/x-foo", "box-foo.desktop")]), text);
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let text =
+            "# managed by hand\n[Default Applications]\n# my browser\ntext/html=firefox.desktop\n";
+        let edited = set(text, &[("text/x-foo", "box-foo.desktop")]);
+        assert_eq!(
+            edited,
+            "# managed by hand\n[Default Applications]\n# my browser\ntext/html=firefox.desktop\ntext/x-foo=box-foo.desktop\n"
+        );
+        assert_eq!(unset(&edited, &[("text/x-foo", "box-foo.desktop")]), text);
+    }
+
+    #[test]
+    fn never_overrides_user_default() {
+        let text = "[Default Applications]\ntext/plain = gedit.desktop\n";
+        let (edited, set) = edit_defaults(text, |group| {
+            set_defaults(
+                group,
+                &BTreeMap::new(),
+                defaults(&[("text/plain", "box-foo.desktop")]),
+            )
+        });
+        assert_eq!(edited, text);
+        assert!(set.is_empty());
+        assert_eq!(unset(text, &[("text/plain", "box-foo.desktop")]), text);
+        // A default the user changed after it was set is theirs now
+        let changed = "[Default Applications]\ntext/x-foo=gedit.desktop\n";
+        assert_eq!(
+            unset(changed, &[("text/x-foo", "box-foo.desktop")]),
+            changed
+        );
+    }
+
+    #[test]
+    fn removal_restores_file() {
+        let text = "[Default Applications]\ntext/plain=a.desktop\n\n[Added Associations]\n";
+        let defaults = [
+            ("text/x-foo", "box-foo.desktop"),
+            ("image/x-bar", "box-bar.desktop"),
+        ];
+        let edited = set(text, &defaults);
+        assert_ne!(edited, text);
+        assert_eq!(unset(&edited, &defaults), text);
+        let edited = set("", &defaults);
+        assert_eq!(unset(&edited, &defaults), "");
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::PathBuf,
+};
+
+use async_std::io::{prelude::BufReadExt, BufRead, ReadExt, Write, WriteExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::server::ContainerReport;
+
+/// newest protocol version we speak
+pub const PROTOCOL_VERSION: u32 = 4;
+/// oldest protocol version we still accept
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// longest message that is read, icons are sent in a single message
+pub const MAX_MESSAGE_LEN: u64 = 256 * 1024 * 1024;
+
+// Messages are sent as one line of json each. A session goes:
+//
+//   client: Hello
+//   host:   Welcome or Rejected
+//   client: any number of Entries, Icons, MimePackages, Metainfo, Autostart,
+//           SearchProviders, DBusServices and Delete, then Done
+//   host:   Report
+//
+// Version 2 added Metainfo, version 3 added Autostart, version 4 added
+// SearchProviders and DBusServices.
+
+/// messages sent from the client inside a container to the host
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ClientMessage {
+    /// first message of every session
+    Hello {
+        /// protocol versions the client speaks, oldest and newest
+        min_version: u32,
+        max_version: u32,
+        container: ContainerMetadata,
+    },
+    Entries(Vec<EntryFile>),
+    Icons(Vec<IconBlob>),
+    MimePackages(Vec<MimePackage>),
+    /// since version 2
+    Metainfo(Vec<MetainfoFile>),
+    /// autostart entries, since version 3
+    Autostart(Vec<EntryFile>),
+    /// gnome-shell search providers and KRunner plugins, since version 4
+    SearchProviders(Vec<EntryFile>),
+    /// D-Bus service files that start the search providers and D-Bus activatable
+    /// apps, since version 4
+    DBusServices(Vec<EntryFile>),
+    Delete(Deletion),
+    /// everything has been sent
+    Done,
+}
+
+impl ClientMessage {
+    /// oldest protocol version that has this message
+    pub fn min_version(&self) -> u32 {
+        match self {
+            ClientMessage::Metainfo(_) => 2,
+            ClientMessage::Autostart(_) => 3,
+            ClientMessage::SearchProviders(_) | ClientMessage::DBusServices(_) => 4,
+            _ => 1,
+        }
+    }
+}
+
+/// messages sent from the host to the client
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ServerMessage {
+    /// the handshake succeeded and the session uses `version`
+    Welcome { version: u32 },
+    /// the handshake failed, the host closes the connection
+    Rejected { reason: String },
+    /// what happened to the entries that were sent
+    Report(Box<ContainerReport>),
+}
+
+/// what the client knows about the container it runs in
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct ContainerMetadata {
+    pub name: String,
+    /// `PRETTY_NAME` from the container's os-release
+    pub os: Option<String>,
+    /// version of the client program
+    pub client_version: String,
+}
+
+/// a desktop file, keyed by its path in the container
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EntryFile {
+    pub path: PathBuf,
+    pub data: Vec<u8>,
+}
+
+/// an icon the entries use
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IconBlob {
+    /// name the entries refer to it by
+    pub name: String,
+    /// path in the container
+    pub path: PathBuf,
+    /// nominal size in pixels, if it comes from a sized theme directory
+    pub size: Option<u32>,
+    pub scalable: bool,
+    pub data: Vec<u8>,
+}
+
+/// a shared-mime-info package from `share/mime/packages`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MimePackage {
+    pub path: PathBuf,
+    pub data: Vec<u8>,
+}
+
+/// an AppStream metainfo file from `share/metainfo` or `share/appdata`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MetainfoFile {
+    pub path: PathBuf,
+    pub data: Vec<u8>,
+}
+
+/// entries the client wants gone
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Deletion {
+    /// every entry of the container, e.g. when it is being removed
+    All,
+    /// the desktop files at these paths
+    Entries(Vec<PathBuf>),
+}
+
+/// everything received in one session, put together
+#[derive(Debug, Default)]
+pub struct Bundle {
+    pub container: ContainerMetadata,
+    /// contents of every desktop file, keyed by path in the container
+    pub desktop_files: HashMap<PathBuf, Vec<u8>>,
+    /// icons the desktop files use, keyed by icon name
+    pub icons: HashMap<String, IconBlob>,
+    pub mime_packages: Vec<MimePackage>,
+    pub metainfo: Vec<MetainfoFile>,
+    /// contents of every autostart entry, keyed by path in the container
+    pub autostart: HashMap<PathBuf, Vec<u8>>,
+    pub search_providers: Vec<EntryFile>,
+    pub dbus_services: Vec<EntryFile>,
+    /// the client asked for every entry to be removed
+    pub delete_all: bool,
+    /// desktop files the client asked to be removed
+    pub deleted: HashSet<PathBuf>,
+}
+
+impl Bundle {
+    pub fn new(container: ContainerMetadata) -> Self {
+        Self {
+            container,
+            ..Default::default()
+        }
+    }
+
+    /// add what `message` carries. returns false once the client is done
+    pub fn apply(&mut self, message: ClientMessage) -> Result<bool, io::Error> {
+        match message {
+            ClientMessage::Hello { .. } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected Hello after the handshake",
+                ))
+            }
+            ClientMessage::Entries(entries) => {
+                for entry in entries {
+                    self.desktop_files.insert(entry.path, entry.data);
+                }
+            }
+            ClientMessage::Icons(icons) => {
+                for icon in icons {
+                    self.icons.insert(icon.name.clone(), icon);
+                }
+            }
+            ClientMessage::MimePackages(packages) => self.mime_packages.extend(packages),
+            ClientMessage::Metainfo(files) => self.metainfo.extend(files),
+            ClientMessage::Autostart(entries) => {
+                for entry in entries {
+                    self.autostart.insert(entry.path, entry.data);
+                }
+            }
+            ClientMessage::SearchProviders(files) => self.search_providers.extend(files),
+            ClientMessage::DBusServices(files) => self.dbus_services.extend(files),
+            ClientMessage::Delete(Deletion::All) => self.delete_all = true,
+            ClientMessage::Delete(Deletion::Entries(paths)) => self.deleted.extend(paths),
+            ClientMessage::Done => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// the version to use with a client speaking `min_version` to `max_version`,
+/// or why there is none
+pub fn negotiate(min_version: u32, max_version: u32) -> Result<u32, String> {
+    let version = max_version.min(PROTOCOL_VERSION);
+    if version < min_version.max(MIN_PROTOCOL_VERSION) {
+        return Err(format!(
+            "no common protocol version: client speaks {}-{}, host speaks {}-{}",
+            min_version, max_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    Ok(version)
+}
+
+/// read the next message, or `None` if the other side hung up
+pub async fn read_message<T: DeserializeOwned>(
+    reader: &mut (impl BufRead + Unpin),
+) -> Result<Option<T>, io::Error> {
+    read_message_max(reader, MAX_MESSAGE_LEN).await
+}
+
+/// read the next message, refusing one longer than `max_len` bytes
+async fn read_message_max<T: DeserializeOwned>(
+    reader: &mut (impl BufRead + Unpin),
+    max_len: u64,
+) -> Result<Option<T>, io::Error> {
+    let mut line = String::new();
+    let len = (&mut *reader)
+        .take(max_len + 1)
+        .read_line(&mut line)
+        .await?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if len as u64 > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message longer than {} bytes", max_len),
+        ));
+    }
+    Ok(Some(serde_json::from_str(&line)?))
+}
+
+pub async fn write_message<T: Serialize>(
+    writer: &mut (impl Write + Unpin),
+    message: &T,
+) -> Result<(), io::Error> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{io::Cursor, task};
+
+    fn read(data: &str, max_len: u64) -> Result<Option<ServerMessage>, io::Error> {
+        task::block_on(read_message_max(&mut Cursor::new(data.as_bytes()), max_len))
+    }
+
+    #[test]
+    fn negotiates_the_newest_common_version() {
+        assert_eq!(negotiate(1, PROTOCOL_VERSION), Ok(PROTOCOL_VERSION));
+        assert_eq!(negotiate(1, 2), Ok(2));
+        assert_eq!(negotiate(2, PROTOCOL_VERSION + 5), Ok(PROTOCOL_VERSION));
+        assert_eq!(negotiate(0, MIN_PROTOCOL_VERSION), Ok(MIN_PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn refuses_clients_without_a_common_version() {
+        assert!(negotiate(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 3).is_err());
+        assert!(negotiate(0, MIN_PROTOCOL_VERSION - 1).is_err());
+        assert!(negotiate(3, 2).is_err());
+    }
+
+    #[test]
+    fn refuses_long_messages() {
+        let line = "{\"Welcome\":{\"version\":1}}\n";
+        let len = line.len() as u64;
+        assert!(matches!(
+            read(line, len),
+            Ok(Some(ServerMessage::Welcome { version: 1 }))
+        ));
+        let e = read(line, len - 1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(read("", len).unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_hello_after_the_handshake() {
+        let mut bundle = Bundle::new(ContainerMetadata::default());
+        assert!(bundle.apply(ClientMessage::Entries(Vec::new())).unwrap());
+        let hello = ClientMessage::Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            container: ContainerMetadata::default(),
+        };
+        let e = bundle.apply(hello).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(!bundle.apply(ClientMessage::Done).unwrap());
+    }
+
+    #[test]
+    fn gates_messages_by_version() {
+        assert_eq!(ClientMessage::Entries(Vec::new()).min_version(), 1);
+        assert_eq!(ClientMessage::Metainfo(Vec::new()).min_version(), 2);
+        assert_eq!(ClientMessage::Autostart(Vec::new()).min_version(), 3);
+        assert_eq!(ClientMessage::SearchProviders(Vec::new()).min_version(), 4);
+        assert_eq!(ClientMessage::DBusServices(Vec::new()).min_version(), 4);
+        // A client talking to a version 2 host holds back what needs a newer one
+        let version = negotiate(MIN_PROTOCOL_VERSION, 2).unwrap();
+        assert!(ClientMessage::Metainfo(Vec::new()).min_version() <= version);
+        assert!(ClientMessage::Autostart(Vec::new()).min_version() > version);
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::HashSet,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    activation::host_bus_names,
+    local_sink::{config_home, data_home, namespaced},
+    manifest::FileManifest,
+    protocol::EntryFile,
+    sink::EntrySink,
+};
+
+/// the value of `key` in an ini style file, ignoring groups
+pub fn ini_value<'a>(text: &'a str, key: &str) -> Option<&'a str> {
+    text.lines().find_map(|line| {
+        let (line_key, value) = line.split_once('=')?;
+        (line_key.trim() == key).then(|| value.trim())
+    })
+}
+
+/// whether `path` is a gnome-shell search provider
+fn is_gnome(path: &Path) -> bool {
+    path.parent()
+        .is_some_and(|dir| dir.ends_with("gnome-shell/search-providers"))
+        && path.extension().and_then(|ext| ext.to_str()) == Some("ini")
+}
+
+/// whether `path` is a KRunner D-Bus plugin
+fn is_krunner(path: &Path) -> bool {
+    path.parent()
+        .is_some_and(|dir| dir.ends_with("krunner/dbusplugins"))
+        && path.extension().and_then(|ext| ext.to_str()) == Some("desktop")
+}
+
+/// whether `path` is a search provider of either kind
+pub fn is_provider(path: &Path) -> bool {
+    is_gnome(path) || is_krunner(path)
+}
+
+/// the bus name a search provider is reached at
+pub fn bus_name(path: &Path, text: &str) -> Option<String> {
+    let key = if is_gnome(path) {
+        "BusName"
+    } else {
+        "X-Plasma-DBusRunner-Service"
+    };
+    ini_value(text, key).map(|name| name.to_string())
+}
+
+/// where gnome-shell search providers are installed. gnome-shell only reads them
+/// from `XDG_DATA_DIRS`, so this directory is added there with an environment.d snippet
+fn gnome_data_dir() -> Result<PathBuf, io::Error> {
+    Ok(data_home()?
+        .join("container-desktop-entries")
+        .join("search-providers"))
+}
+
+/// install the search providers of `container_name` whose app is exported, replacing
+/// the ones installed before. gnome-shell providers are pointed at the entry as it
+/// is installed on the host. returns the bus names the providers need activated
+pub fn export_search_providers(
+    sink: &dyn EntrySink,
+    container_name: &str,
+    appids: &HashSet<&str>,
+    providers: &[EntryFile],
+) -> Result<HashSet<String>, io::Error> {
+    let mut manifest = FileManifest::load("search-providers")?;
+    manifest.remove_container(container_name);
+    let host_names = host_bus_names();
+    let mut bus_names = HashSet::new();
+    for provider in providers.iter() {
+        let Some(file_name) = provider.path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let Ok(text) = std::str::from_utf8(&provider.data) else {
+            log::warn!(
+                "Ignoring search provider {:?}, not valid utf-8",
+                provider.path
+            );
+            continue;
+        };
+        let Some(bus_name) = bus_name(&provider.path, text) else {
+            log::warn!(
+                "Ignoring search provider {:?} without a bus name",
+                provider.path
+            );
+            continue;
+        };
+        if host_names.contains(&bus_name) {
+            // Activating it would start the host's provider, not the container's
+            log::info!(
+                "Skipping search provider {:?}, the host already provides {}",
+                provider.path,
+                bus_name
+            );
+            continue;
+        }
+        let (path, text) = if is_gnome(&provider.path) {
+            let Some(appid) = ini_value(text, "DesktopId")
+                .map(|id| id.strip_suffix(".desktop").unwrap_or(id))
+                .filter(|appid| appids.contains(appid))
+            else {
+                // gnome-shell drops providers whose app it can't find
+                log::debug!("Skipping {:?}, its app is not exported", provider.path);
+                continue;
+            };
+            let desktop_id = sink.desktop_id(container_name, appid);
+            let text: Vec<String> = text
+                .lines()
+                .map(|line| match line.split_once('=') {
+                    Some((key, _)) if key.trim() == "DesktopId" => {
+                        format!("DesktopId={}", desktop_id)
+                    }
+                    _ => line.to_string(),
+                })
+                .collect();
+            (
+                gnome_data_dir()?
+                    .join("gnome-shell")
+                    .join("search-providers")
+                    .join(namespaced(container_name, file_name)),
+                text.join("\n") + "\n",
+            )
+        } else if is_krunner(&provider.path) {
+            (
+                data_home()?
+                    .join("krunner")
+                    .join("dbusplugins")
+                    .join(namespaced(container_name, file_name)),
+                text.to_string(),
+            )
+        } else {
+            continue;
+        };
+        manifest.write(container_name, &path, text.as_bytes())?;
+        bus_names.insert(bus_name);
+    }
+    manifest.save()?;
+    if !bus_names.is_empty() {
+        log::info!(
+            "{}: exported {} search providers",
+            container_name,
+            bus_names.len()
+        );
+    }
+    write_environment()?;
+    Ok(bus_names)
+}
+
+/// remove the search providers installed for `container_name`
+pub fn remove_search_providers(container_name: &str) -> Result<(), io::Error> {
+    let mut manifest = FileManifest::load("search-providers")?;
+    manifest.remove_container(container_name);
+    manifest.save()
+}
+
+/// write `$XDG_CONFIG_HOME/environment.d/61-container-desktop-entries-search.conf`,
+/// which adds the gnome-shell search providers to `XDG_DATA_DIRS` from the next login on
+fn write_environment() -> Result<(), io::Error> {
+    let data_dir = gnome_data_dir()?;
+    if !data_dir.exists()
+        || env::var("XDG_DATA_DIRS")
+            .unwrap_or_default()
+            .split(':')
+            .any(|dir| Path::new(dir) == data_dir)
+    {
+        return Ok(());
+    }
+    let path = config_home()?
+        .join("environment.d")
+        .join("61-container-desktop-entries-search.conf");
+    let contents = format!(
+        "XDG_DATA_DIRS=${{XDG_DATA_DIRS:-/usr/local/share:/usr/share}}:{}\n",
+        data_dir.to_str().unwrap()
+    );
+    if fs::read_to_string(&path).ok().as_deref() != Some(contents.as_str()) {
+        log::info!(
+            "Adding {:?} to XDG_DATA_DIRS, log in again for gnome-shell search providers to show up",
+            data_dir
+        );
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, contents)?;
+    }
+    Ok(())
+}
@@ -1,23 +1,42 @@
 use std::{
-    env,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::OsString,
     fmt::Display,
-    fs::{self, read, read_to_string},
-    io,
+    io::{self, Read},
     path::{Path, PathBuf},
-    process::Command,
+    process::Output,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use async_process::Command;
+use async_std::{future, task};
+use futures_util::{stream, StreamExt};
+
 use freedesktop_desktop_entry::DesktopEntry;
 use regex::Regex;
-use walkdir::WalkDir;
-use zbus::Connection;
+use serde::{Deserialize, Serialize};
+use zbus::connection;
 
-use crate::{container_type::ContainerType, desktop_entry::DesktopEntryProxy, ContainerList};
+use crate::{
+    activation, autostart,
+    cache::{self, Cache, ContainerStatus, Manifest, PushedEntries, PushedState, SyncStatus},
+    client,
+    container_type::{shell_quote, ContainerType},
+    icon, menu, metainfo, mime,
+    protocol::{Bundle, EntryFile, MetainfoFile, MimePackage},
+    search_provider,
+    service::{ContainerDesktopEntries, SERVICE_NAME, SERVICE_PATH},
+    shims,
+    sink::{self, EntrySink},
+    ContainerList, ContainerSettings, EntryLifetime,
+};
 
 #[derive(Debug)]
 pub enum ClientSetupError {
     IO(io::Error),
     Zbus(zbus::Error),
+    /// the container did not start after this many attempts
+    NotReady(u32),
 }
 
 impl From<io::Error> for ClientSetupError {
@@ -37,108 +56,1212 @@ impl Display for ClientSetupError {
         match self {
             Self::IO(e) => e.fmt(f),
             Self::Zbus(e) => e.fmt(f),
+            Self::NotReady(attempts) => {
+                write!(f, "container not ready after {} attempts", attempts)
+            }
         }
     }
 }
 
+/// sync every container, then keep serving the D-Bus interface so syncs can be
+/// triggered without restarting
 pub async fn server(containers: ContainerList, owner: &str) -> Result<(), ClientSetupError> {
-    let runtime_dir_str = env::var("RUNTIME_DIRECTORY").unwrap_or(format!(
-        "/run/user/{}/container-desktop-entries/",
-        env::var("UID").unwrap_or("1000".to_string())
-    ));
-    let tmp_dir = Path::new(&runtime_dir_str);
-    if !tmp_dir.exists() {
-        log::warn!(
-            "tmp_dir {} does not exist! creating directory...",
-            tmp_dir.to_str().unwrap()
+    let server = Server::new(containers, owner).await?;
+    let connection = connection::Builder::session()?
+        .name(SERVICE_NAME)?
+        .serve_at(SERVICE_PATH, ContainerDesktopEntries::new(server))?
+        .build()
+        .await?;
+    let iface = connection
+        .object_server()
+        .interface::<_, ContainerDesktopEntries>(SERVICE_PATH)
+        .await?;
+    if let Err(e) = iface
+        .get_mut()
+        .await
+        .sync_all_and_signal(iface.signal_context())
+        .await
+    {
+        log::error!("Initial sync failed: {}", e);
+    }
+    if let Err(e) = client::serve_clients(iface.clone()).await {
+        log::error!("Could not listen for clients: {}", e);
+    }
+    log::info!("Serving {} on the session bus", SERVICE_NAME);
+    future::pending::<()>().await;
+    Ok(())
+}
+
+/// what happened to one container during a sync or removal
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ContainerReport {
+    pub container: String,
+    pub status: ReportStatus,
+    /// how long fetching the container's entries took
+    pub duration_ms: u64,
+    /// appids of every entry the container exports
+    pub exported: Vec<String>,
+    /// appids that were registered or updated
+    pub added: Vec<String>,
+    /// appids that are no longer registered
+    pub removed: Vec<String>,
+    /// desktop files that were not exported, and why
+    pub skipped: Vec<SkippedEntry>,
+    /// icon names, and the path in the container they were found at
+    pub icons_found: BTreeMap<String, PathBuf>,
+    /// icon names that could not be found or used, and got the placeholder instead
+    pub icons_missing: BTreeSet<String>,
+    /// appids and icon names the sink failed to register, and why
+    #[serde(default)]
+    pub failed: BTreeMap<String, String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    #[default]
+    Ok,
+    Failed,
+    Removed,
+}
+
+/// a desktop file in the container that was not exported
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SkippedEntry {
+    pub path: PathBuf,
+    pub reason: SkipReason,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum SkipReason {
+    /// `NoDisplay=true`
+    NoDisplay,
+    /// not a valid desktop entry
+    ParseError,
+    /// not valid utf-8
+    InvalidUtf8,
+    /// a file with the same desktop file id in an earlier data dir is used instead
+    Shadowed,
+    /// `DBusActivatable=true` without an `Exec=` to start it through the container
+    NoExec,
+}
+
+/// the configured containers along with what has been pushed for them, shared by
+/// everything that syncs or removes entries
+pub struct Server {
+    pub containers: ContainerList,
+    sink: Box<dyn EntrySink>,
+    pushed: PushedState,
+}
+
+impl Server {
+    pub async fn new(containers: ContainerList, owner: &str) -> Result<Self, ClientSetupError> {
+        let sink = sink::open_sink(containers.backend, owner).await?;
+        let pushed = PushedState::load();
+        if !pushed
+            .containers
+            .values()
+            .any(|previous| previous.lifetime == EntryLifetime::Session)
+        {
+            // Nothing pushed yet this session. Clear what older versions registered
+            // under a single owner for every container
+            if let Err(e) = sink.remove_legacy().await {
+                log::error!("could not remove owner {}: {}", owner, e);
+            }
+        }
+        Ok(Self {
+            containers,
+            sink,
+            pushed,
+        })
+    }
+
+    /// names of every configured container
+    pub fn configured(&self) -> HashSet<&str> {
+        self.containers
+            .containers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// sync every configured container, removing the entries of containers that
+    /// left the config and of those registered with a lifetime no longer used
+    pub async fn sync_all(&mut self) -> Result<Vec<ContainerReport>, ClientSetupError> {
+        let names: Vec<String> = self
+            .configured()
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        let stale: Vec<(String, EntryLifetime)> = self
+            .pushed
+            .containers
+            .iter()
+            .filter(|(name, previous)| {
+                !names.contains(name)
+                    || self.containers.settings(name).lifetime != previous.lifetime
+            })
+            .map(|(name, previous)| (name.clone(), previous.lifetime))
+            .collect();
+        let mut reports = Vec::new();
+        for (container_name, lifetime) in stale {
+            reports.push(
+                remove_client(
+                    self.sink.as_ref(),
+                    &container_name,
+                    lifetime,
+                    &mut self.pushed,
+                )
+                .await,
+            );
+        }
+        reports.extend(self.sync(&names).await?);
+        Ok(reports)
+    }
+
+    /// sync the containers in `names`, leaving every other container's entries alone
+    pub async fn sync(
+        &mut self,
+        names: &[String],
+    ) -> Result<Vec<ContainerReport>, ClientSetupError> {
+        let names: HashSet<&str> = names.iter().map(|name| name.as_str()).collect();
+        let result = sync_clients(
+            self.sink.as_ref(),
+            &self.containers,
+            &names,
+            &mut self.pushed,
+        )
+        .await;
+        if let Err(e) = shims::export_shims(&self.containers, &names) {
+            log::error!("could not export binaries: {}", e);
+        }
+        self.finish().await?;
+        let reports = result?;
+        record_status(&reports);
+        Ok(reports)
+    }
+
+    /// remove every entry of `container_name` until it is synced again
+    pub async fn remove(
+        &mut self,
+        container_name: &str,
+    ) -> Result<ContainerReport, ClientSetupError> {
+        let lifetime = self.containers.settings(container_name).lifetime;
+        let report = remove_client(
+            self.sink.as_ref(),
+            container_name,
+            lifetime,
+            &mut self.pushed,
+        )
+        .await;
+        if let Err(e) = shims::remove_shims(container_name) {
+            log::error!("could not remove binaries of {}: {}", container_name, e);
+        }
+        self.finish().await?;
+        Ok(report)
+    }
+
+    /// register the entries a client inside a container sent us, in place of
+    /// fetching them from the host
+    pub async fn push_bundle(
+        &mut self,
+        bundle: Bundle,
+    ) -> Result<ContainerReport, ClientSetupError> {
+        let container_name = bundle.container.name.as_str();
+        let container_type = self
+            .containers
+            .containers
+            .iter()
+            .find(|(name, _)| name == container_name)
+            .map(|(_, container_type)| *container_type)
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a configured container", container_name),
+            ))?;
+        if bundle.delete_all {
+            return self.remove(container_name).await;
+        }
+        if let Err(e) = shims::export_shims(&self.containers, &HashSet::from([container_name])) {
+            log::error!("could not export binaries: {}", e);
+        }
+        let mut skipped = Vec::new();
+        let mut entries = read_entries(
+            container_name,
+            container_type,
+            bundle
+                .desktop_files
+                .iter()
+                .filter(|(path, _)| !bundle.deleted.contains(*path))
+                .map(|(path, data)| (path.clone(), data.clone())),
+            &mut skipped,
         );
-        fs::create_dir(tmp_dir).unwrap();
-    }
-    let connection = Connection::session().await?;
-    let proxy = DesktopEntryProxy::new(&connection).await?;
-    if let Err(e) = proxy.remove_session_owner(&owner).await {
-        log::error!("could not remove owner container-desktop-entries: {:?}", e);
-    }
-    for (container_name, container_type) in containers.containers {
-        if container_type.not_supported() {
-            log::error!(
-                "Container type {:?} is currently not supported!",
-                container_type
+        attach_icons(container_name, &mut entries, |name| {
+            let icon = bundle.icons.get(name)?;
+            Some((icon.path.clone(), icon.data.clone()))
+        });
+        let autostart = if self.containers.settings(container_name).autostart {
+            read_autostart(container_name, container_type, bundle.autostart.into_iter())
+        } else {
+            Vec::new()
+        };
+        if self.containers.settings(container_name).terminal {
+            entries.extend(terminal_entry(
+                container_name,
+                container_type,
+                self.containers.terminal_command.as_deref(),
+            ));
+        }
+        let setup = ClientSetup {
+            container: container_name.to_string(),
+            duration: Duration::ZERO,
+            result: Ok(ClientEntries {
+                entries,
+                skipped,
+                mime_packages: bundle.mime_packages,
+                metainfo: bundle.metainfo,
+                autostart,
+                search_providers: bundle.search_providers,
+                dbus_services: bundle.dbus_services,
+            }),
+        };
+        let mut report = setup.report();
+        if let Ok(client) = setup.result {
+            let settings = self.containers.settings(container_name);
+            replace_entries(
+                self.sink.as_ref(),
+                container_name,
+                settings.lifetime,
+                &client.entries,
+                &mut self.pushed,
+                &mut report,
+            )
+            .await;
+            export_extras(
+                self.sink.as_ref(),
+                container_name,
+                container_type,
+                &client,
+                &settings,
             );
-            continue;
         }
-        if let Err(kind) = set_up_client(&container_name, container_type, &tmp_dir, owner).await {
-            log::error!("Error setting up client {}: {:?}", container_name, kind);
+        self.finish().await?;
+        record_status(std::slice::from_ref(&report));
+        Ok(report)
+    }
+
+    async fn finish(&mut self) -> Result<(), ClientSetupError> {
+        if let Err(e) = self.sink.finish().await {
+            log::error!("could not finish writing entries: {}", e);
+        }
+        self.pushed.save()?;
+        if let Err(e) = Cache::open()?.collect_garbage(&self.configured()) {
+            log::error!("could not clean up cache: {}", e);
         }
+        Ok(())
     }
-    Ok(())
 }
 
-async fn set_up_client(
+/// remember when the containers in `reports` were synced and how it went
+fn record_status(reports: &[ContainerReport]) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut status = SyncStatus::load();
+    for report in reports.iter() {
+        status.containers.insert(
+            report.container.clone(),
+            ContainerStatus {
+                last_sync: now,
+                error: report.error.clone(),
+            },
+        );
+    }
+    if let Err(e) = status.save() {
+        log::error!("could not save sync status: {}", e);
+    }
+    if let Err(e) = cache::save_report(reports) {
+        log::error!("could not save sync report: {}", e);
+    }
+}
+
+/// the outcome of gathering one container's entries
+pub struct ClientSetup {
+    pub container: String,
+    pub duration: Duration,
+    pub result: Result<ClientEntries, ClientSetupError>,
+}
+
+/// everything a container exports, along with the desktop files it doesn't
+pub struct ClientEntries {
+    pub entries: Vec<PendingEntry>,
+    pub skipped: Vec<SkippedEntry>,
+    /// shared-mime-info packages from the container's data dirs
+    pub mime_packages: Vec<MimePackage>,
+    /// AppStream metainfo of the exported entries
+    pub metainfo: Vec<MetainfoFile>,
+    /// appids and rewritten file texts of autostart entries, if they are exported
+    pub autostart: Vec<(String, String)>,
+    /// gnome-shell search providers and KRunner plugins
+    pub search_providers: Vec<EntryFile>,
+    /// D-Bus service files the search providers and D-Bus activatable apps are
+    /// started with
+    pub dbus_services: Vec<EntryFile>,
+}
+
+impl ClientSetup {
+    /// a report for this container, without the changes a push makes
+    pub fn report(&self) -> ContainerReport {
+        let mut report = ContainerReport {
+            container: self.container.clone(),
+            duration_ms: self.duration.as_millis() as u64,
+            ..Default::default()
+        };
+        match self.result.as_ref() {
+            Ok(client) => {
+                report.skipped = client.skipped.clone();
+                for entry in client.entries.iter() {
+                    report.exported.push(entry.appid.clone());
+                    if let Some(path) = entry.icon_path.as_ref() {
+                        report
+                            .icons_found
+                            .insert(entry.icon_name.clone(), path.clone());
+                    }
+                    if let Some(icon_name) = entry.missing_icon.as_ref() {
+                        report.icons_missing.insert(icon_name.clone());
+                    }
+                }
+            }
+            Err(e) => {
+                report.status = ReportStatus::Failed;
+                report.error = Some(e.to_string());
+            }
+        }
+        report
+    }
+}
+
+/// gather the entries of the containers in `names`, a few containers at a time.
+/// a `dry_run` leaves the cache untouched
+pub async fn set_up_clients(
+    containers: &ContainerList,
+    names: &HashSet<&str>,
+    dry_run: bool,
+) -> Result<Vec<ClientSetup>, ClientSetupError> {
+    let cache = if dry_run {
+        Cache::open_read_only()?
+    } else {
+        Cache::open()?
+    };
+    let supported: Vec<(String, ContainerType, ContainerSettings)> = containers
+        .containers
+        .iter()
+        .filter(|(container_name, _)| names.contains(container_name.as_str()))
+        .filter(|(_, container_type)| {
+            if container_type.not_supported() {
+                log::error!(
+                    "Container type {:?} is currently not supported!",
+                    container_type
+                );
+            }
+            !container_type.not_supported()
+        })
+        .map(|(container_name, container_type)| {
+            (
+                container_name.clone(),
+                *container_type,
+                containers.settings(container_name),
+            )
+        })
+        .collect();
+    let cache = &cache;
+    let terminal_command = containers.terminal_command.as_deref();
+    Ok(stream::iter(supported)
+        .map(|(container_name, container_type, settings)| async move {
+            let start = Instant::now();
+            let mut result = set_up_client(&container_name, container_type, &settings, cache).await;
+            if let (Ok(client), true) = (result.as_mut(), settings.terminal) {
+                client.entries.extend(terminal_entry(
+                    &container_name,
+                    container_type,
+                    terminal_command,
+                ));
+            }
+            ClientSetup {
+                container: container_name,
+                duration: start.elapsed(),
+                result,
+            }
+        })
+        .buffer_unordered(containers.max_concurrent.max(1))
+        .collect()
+        .await)
+}
+
+/// sync the containers in `names` into `sink`
+async fn sync_clients(
+    sink: &dyn EntrySink,
+    containers: &ContainerList,
+    names: &HashSet<&str>,
+    pushed: &mut PushedState,
+) -> Result<Vec<ContainerReport>, ClientSetupError> {
+    let results = set_up_clients(containers, names, false).await?;
+    let mut reports = Vec::new();
+    let mut not_ready = Vec::new();
+    for setup in results.iter() {
+        let container_name = setup.container.as_str();
+        let mut report = setup.report();
+        let client = match setup.result.as_ref() {
+            Ok(client) => client,
+            Err(kind) => {
+                log::error!("Error setting up client {}: {}", container_name, kind);
+                if let ClientSetupError::NotReady(_) = kind {
+                    not_ready.push(container_name);
+                }
+                reports.push(report);
+                continue;
+            }
+        };
+        let settings = containers.settings(container_name);
+        replace_entries(
+            sink,
+            container_name,
+            settings.lifetime,
+            &client.entries,
+            pushed,
+            &mut report,
+        )
+        .await;
+        let container_type = containers
+            .containers
+            .iter()
+            .find(|(name, _)| name == container_name)
+            .map_or(ContainerType::Unknown, |(_, container_type)| {
+                *container_type
+            });
+        export_extras(sink, container_name, container_type, client, &settings);
+        reports.push(report);
+    }
+    if !not_ready.is_empty() {
+        log::error!(
+            "Containers that never became ready: {}",
+            not_ready.join(", ")
+        );
+    }
+    Ok(reports)
+}
+
+/// make `entries` what is registered for a container, recording what changed in `report`
+async fn replace_entries(
+    sink: &dyn EntrySink,
+    container_name: &str,
+    lifetime: EntryLifetime,
+    entries: &[PendingEntry],
+    pushed: &mut PushedState,
+    report: &mut ContainerReport,
+) {
+    // Sinks can only remove a container's entries all at once, so if anything went away
+    // since the last push, start this container over. Process entries of an earlier
+    // run are already gone
+    let start_over = match pushed.containers.get(container_name) {
+        Some(previous) => has_removals(previous, entries),
+        None => lifetime != EntryLifetime::Process,
+    };
+    if start_over {
+        let removal = remove_client(sink, container_name, lifetime, pushed).await;
+        if removal.status == ReportStatus::Failed {
+            // What went away stays registered, push the rest over it
+            report.status = ReportStatus::Failed;
+            report.error = removal.error;
+        }
+        report.removed = removal
+            .removed
+            .into_iter()
+            .filter(|appid| !entries.iter().any(|entry| &entry.appid == appid))
+            .collect();
+    }
+    let previous = pushed
+        .containers
+        .entry(container_name.to_string())
+        .or_insert_with(|| PushedEntries {
+            lifetime,
+            ..Default::default()
+        });
+    push_client(sink, container_name, entries, previous, report).await;
+    if !report.failed.is_empty() {
+        report.status = ReportStatus::Failed;
+        report.error = Some(format!(
+            "could not register {}",
+            report.failed.keys().cloned().collect::<Vec<_>>().join(", ")
+        ));
+    }
+}
+
+/// install the mime packages, default applications, metainfo, autostart entries,
+/// search providers, D-Bus services and submenu of a synced container
+fn export_extras(
+    sink: &dyn EntrySink,
     container_name: &str,
     container_type: ContainerType,
-    to_path: &Path,
-    owner: &str,
-) -> Result<(), ClientSetupError> {
-    // Start client if client is not running
-    start_client(container_name, container_type)?;
-    let _ = fs::create_dir(&to_path.join("applications"));
-    let _ = fs::create_dir(&to_path.join("icons"));
-    let _ = fs::create_dir(&to_path.join("pixmaps"));
-    // Find the data dirs and iterate over them
-    let data_dirs = run_in_client(
+    client: &ClientEntries,
+    settings: &ContainerSettings,
+) {
+    if let Err(e) = mime::export_mime(
+        sink,
+        container_name,
+        &client.mime_packages,
+        &settings.mime_defaults,
+    ) {
+        log::error!("could not export mime types of {}: {}", container_name, e);
+    }
+    if let Err(e) = metainfo::export_metainfo(sink, container_name, &client.metainfo) {
+        log::error!("could not export metainfo of {}: {}", container_name, e);
+    }
+    if let Err(e) = autostart::export_autostart(container_name, &client.autostart) {
+        log::error!(
+            "could not export autostart entries of {}: {}",
+            container_name,
+            e
+        );
+    }
+    let appids: HashSet<&str> = client.entries.iter().map(|e| e.appid.as_str()).collect();
+    let mut bus_names = search_provider::export_search_providers(
+        sink,
+        container_name,
+        &appids,
+        &client.search_providers,
+    )
+    .unwrap_or_else(|e| {
+        log::error!(
+            "could not export search providers of {}: {}",
+            container_name,
+            e
+        );
+        HashSet::new()
+    });
+    if let Err(e) = menu::export_menu(sink, container_name) {
+        log::error!("could not export menu of {}: {}", container_name, e);
+    }
+    bus_names.extend(
+        client
+            .entries
+            .iter()
+            .filter(|entry| entry.dbus_activatable)
+            .map(|entry| entry.appid.clone()),
+    );
+    if let Err(e) = activation::export_services(
+        sink,
         container_name,
         container_type,
-        "env | grep XDG_DATA_DIRS | cut -d'=' -f2",
-        true,
-    )?
-    .unwrap()
-    .trim()
-    .to_string();
-    log::debug!("Data dirs: '{}'", data_dirs);
-    for x in data_dirs.split(":").map(|p| Path::new(p)) {
-        copy_from_client(
+        &client.dbus_services,
+        &bus_names,
+    ) {
+        log::error!(
+            "could not export D-Bus services of {}: {}",
             container_name,
-            container_type,
-            &x.join("applications"),
-            &to_path.join("applications"),
-        )?;
-        copy_from_client(
+            e
+        );
+    }
+}
+
+/// remove every entry and icon registered for one container with `lifetime`
+async fn remove_client(
+    sink: &dyn EntrySink,
+    container_name: &str,
+    lifetime: EntryLifetime,
+    pushed: &mut PushedState,
+) -> ContainerReport {
+    let mut report = ContainerReport {
+        container: container_name.to_string(),
+        status: ReportStatus::Removed,
+        ..Default::default()
+    };
+    // Remove the entries first, so nothing else is touched if the sink can't
+    if let Err(e) = sink.remove(container_name, lifetime).await {
+        log::warn!("could not remove entries of {}: {}", container_name, e);
+        report.status = ReportStatus::Failed;
+        report.error = Some(e.to_string());
+        return report;
+    }
+    log::info!("Removed entries of {}", container_name);
+    if let Some(previous) = pushed.containers.remove(container_name) {
+        report.removed = previous.entries.into_keys().collect();
+    }
+    if let Err(e) = mime::remove_mime(sink, container_name) {
+        log::error!("could not remove mime types of {}: {}", container_name, e);
+    }
+    if let Err(e) = metainfo::remove_metainfo(container_name) {
+        log::error!("could not remove metainfo of {}: {}", container_name, e);
+    }
+    if let Err(e) = search_provider::remove_search_providers(container_name) {
+        log::error!(
+            "could not remove search providers of {}: {}",
             container_name,
-            container_type,
-            &x.join("icons"),
-            &to_path.join("icons"),
-        )?;
+            e
+        );
+    }
+    if let Err(e) = activation::remove_services(container_name) {
+        log::error!(
+            "could not remove D-Bus services of {}: {}",
+            container_name,
+            e
+        );
+    }
+    if let Err(e) = menu::remove_menu(container_name) {
+        log::error!("could not remove menu of {}: {}", container_name, e);
     }
-    copy_from_client(
+    if let Err(e) = autostart::remove_autostart(container_name) {
+        log::error!(
+            "could not remove autostart entries of {}: {}",
+            container_name,
+            e
+        );
+    }
+    report
+}
+
+/// a desktop entry that has been rewritten for the host and is ready to register
+pub struct PendingEntry {
+    pub appid: String,
+    pub file_text: String,
+    pub icon_name: String,
+    /// path of the icon in the container, `None` if the placeholder is used
+    pub icon_path: Option<PathBuf>,
+    pub icon_data: Vec<u8>,
+    /// icon the entry asked for that couldn't be used, the placeholder replaces it
+    pub missing_icon: Option<String>,
+    /// the entry had `DBusActivatable=true`, so its appid is a bus name the
+    /// container's app can be started with
+    pub dbus_activatable: bool,
+}
+
+/// gather the entries a container exports, fetching only files that aren't cached yet
+async fn set_up_client(
+    container_name: &str,
+    container_type: ContainerType,
+    settings: &ContainerSettings,
+    cache: &Cache,
+) -> Result<ClientEntries, ClientSetupError> {
+    // Start client if client is not running
+    start_client(container_name, container_type, settings).await?;
+    let timeout = Duration::from_secs(settings.timeout);
+    let env = run_in_client(
+        container_name,
+        container_type,
+        &format!(
+            "sh -c {}",
+            shell_quote(concat!(
+                r#"echo "${XDG_DATA_DIRS:-/usr/local/share:/usr/share}"; "#,
+                r#"echo "${XDG_CONFIG_HOME:-$HOME/.config}"; "#,
+                r#"echo "${XDG_CONFIG_DIRS:-/etc/xdg}""#
+            ))
+        ),
+        timeout,
+    )
+    .await?;
+    let mut env = env.lines().map(|line| line.trim());
+    let data_dirs: Vec<&Path> = env
+        .next()
+        .unwrap_or("")
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(Path::new)
+        .collect();
+    log::debug!("Data dirs: {:?}", data_dirs);
+    let config_home = env.next().unwrap_or("");
+    let config_dirs = env.next().unwrap_or("/etc/xdg");
+    let in_data_dirs = |sub_dir: &[&str]| -> Vec<PathBuf> {
+        data_dirs
+            .iter()
+            .flat_map(|dir| sub_dir.iter().map(|sub_dir| dir.join(sub_dir)))
+            .collect()
+    };
+    let application_dirs = in_data_dirs(&["applications"]);
+    let mime_dirs = in_data_dirs(&["mime/packages"]);
+    // metainfo is preferred over the older appdata location
+    let metainfo_dirs = in_data_dirs(&["metainfo", "appdata"]);
+    let provider_dirs = in_data_dirs(&["gnome-shell/search-providers", "krunner/dbusplugins"]);
+    let service_dirs = in_data_dirs(&["dbus-1/services"]);
+    let mut autostart_dirs = Vec::new();
+    if settings.autostart {
+        // With a shared home directory, the host already runs the user's autostart entries
+        if !container_type.shares_home() && !config_home.is_empty() {
+            autostart_dirs.push(Path::new(config_home).join("autostart"));
+        }
+        autostart_dirs.extend(
+            config_dirs
+                .split(':')
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| Path::new(dir).join("autostart")),
+        );
+    }
+
+    // List every dir at once, so a sync takes as few trips into the container as possible
+    let listing = list_dirs(
+        container_name,
+        container_type,
+        [
+            &application_dirs,
+            &mime_dirs,
+            &metainfo_dirs,
+            &provider_dirs,
+            &service_dirs,
+            &autostart_dirs,
+        ]
+        .into_iter()
+        .flatten(),
+        timeout,
+    )
+    .await?;
+    let (desktop_files, shadowed) = rank_files(&listing, &application_dirs, ".desktop");
+    let mut skipped: Vec<SkippedEntry> = shadowed
+        .into_iter()
+        .map(|path| SkippedEntry {
+            path,
+            reason: SkipReason::Shadowed,
+        })
+        .collect();
+    let (mime_files, _) = rank_files(&listing, &mime_dirs, ".xml");
+    // Metainfo is only fetched for apps that have a desktop file
+    let desktop_ids: HashSet<&str> = desktop_files
+        .keys()
+        .filter_map(|path| path.file_stem()?.to_str())
+        .collect();
+    let mut metainfo_candidates: Vec<(usize, &PathBuf, &String)> = listing
+        .iter()
+        .filter(|(path, _)| metainfo::appid(path).is_some_and(|id| desktop_ids.contains(id)))
+        .filter_map(|(path, hash)| {
+            let rank = metainfo_dirs
+                .iter()
+                .position(|dir| path.parent() == Some(dir.as_path()))?;
+            Some((rank, path, hash))
+        })
+        .collect();
+    metainfo_candidates.sort();
+    let mut metainfo_files = HashMap::new();
+    let mut seen = HashSet::new();
+    for (_, path, hash) in metainfo_candidates {
+        if seen.insert(metainfo::appid(path).unwrap()) {
+            metainfo_files.insert(path.clone(), hash.clone());
+        }
+    }
+    let (autostart_files, _) = rank_files(&listing, &autostart_dirs, ".desktop");
+    let (mut provider_files, _) = rank_files(&listing, &provider_dirs, "");
+    provider_files.retain(|path, _| search_provider::is_provider(path));
+    // Which services are needed is only known once the entries and providers are
+    // read, but service files are small, so all of them are fetched along
+    let (service_files, _) = rank_files(&listing, &service_dirs, ".service");
+
+    let previous = cache.manifest(container_name);
+    log::info!(
+        "{}: {} of {} desktop files changed since the last sync",
+        container_name,
+        desktop_files
+            .iter()
+            .filter(|(path, hash)| previous.desktop_files.get(*path) != Some(*hash))
+            .count(),
+        desktop_files.len()
+    );
+    let all_files: HashMap<PathBuf, String> = [
+        &desktop_files,
+        &mime_files,
+        &metainfo_files,
+        &autostart_files,
+        &provider_files,
+        &service_files,
+    ]
+    .into_iter()
+    .flat_map(|files| {
+        files
+            .iter()
+            .map(|(path, hash)| (path.clone(), hash.clone()))
+    })
+    .collect();
+    let mut data = fetch_cached(container_name, container_type, cache, &all_files, timeout).await?;
+    let mut take = |files: &HashMap<PathBuf, String>| -> Vec<(PathBuf, Vec<u8>)> {
+        let mut taken: Vec<(PathBuf, Vec<u8>)> = files
+            .keys()
+            .filter_map(|path| Some((path.clone(), data.remove(path)?)))
+            .collect();
+        taken.sort();
+        taken
+    };
+    let desktop_data = take(&desktop_files);
+    let mime_packages: Vec<MimePackage> = take(&mime_files)
+        .into_iter()
+        .map(|(path, data)| MimePackage { path, data })
+        .collect();
+    let metainfo_data = take(&metainfo_files);
+    let autostart_data = take(&autostart_files);
+    let search_providers: Vec<EntryFile> = take(&provider_files)
+        .into_iter()
+        .map(|(path, data)| EntryFile { path, data })
+        .collect();
+    let service_data = take(&service_files);
+
+    let mut entries = read_entries(
+        container_name,
+        container_type,
+        desktop_data.into_iter(),
+        &mut skipped,
+    );
+
+    // Only fetch the icons that the entries we export actually use
+    let placeholder_name = icon::placeholder_name(container_name);
+    let icon_names: HashSet<&str> = entries
+        .iter()
+        .map(|e| e.icon_name.as_str())
+        .filter(|name| *name != placeholder_name)
+        .collect();
+    let icon_paths = resolve_icons(
         container_name,
         container_type,
-        Path::new("/usr/share/pixmaps"),
-        &to_path.join("pixmaps"),
+        &data_dirs,
+        &icon_names,
+        timeout,
+    )
+    .await?;
+    let icon_files: HashMap<PathBuf, String> = icon_paths.values().cloned().collect();
+    let icon_data =
+        fetch_cached(container_name, container_type, cache, &icon_files, timeout).await?;
+
+    attach_icons(container_name, &mut entries, |name| {
+        let (icon_path, _) = icon_paths.get(name)?;
+        Some((icon_path.clone(), icon_data.get(icon_path)?.clone()))
+    });
+
+    let exported: HashSet<&str> = entries.iter().map(|e| e.appid.as_str()).collect();
+    let metainfo: Vec<MetainfoFile> = metainfo_data
+        .into_iter()
+        .filter(|(path, _)| metainfo::appid(path).is_some_and(|id| exported.contains(id)))
+        .map(|(path, data)| MetainfoFile { path, data })
+        .collect();
+    let autostart = read_autostart(container_name, container_type, autostart_data.into_iter());
+
+    // Search providers and D-Bus activatable apps need the services that start them
+    let bus_names: HashSet<String> = search_providers
+        .iter()
+        .filter_map(|provider| {
+            search_provider::bus_name(&provider.path, std::str::from_utf8(&provider.data).ok()?)
+        })
+        .chain(
+            entries
+                .iter()
+                .filter(|entry| entry.dbus_activatable)
+                .map(|entry| entry.appid.clone()),
+        )
+        .collect();
+    let dbus_services: Vec<EntryFile> = service_data
+        .into_iter()
+        .filter(|(path, _)| {
+            path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(|stem| bus_names.contains(stem))
+        })
+        .map(|(path, data)| EntryFile { path, data })
+        .collect();
+
+    cache.save_manifest(
+        container_name,
+        &Manifest {
+            desktop_files,
+            icons: icon_files,
+            mime_packages: mime_files,
+            metainfo: metainfo_files,
+            autostart: autostart_files,
+            search_providers: provider_files,
+            dbus_services: service_files,
+        },
     )?;
-    let connection = Connection::session().await?;
-    let proxy = DesktopEntryProxy::new(&connection).await?;
+    Ok(ClientEntries {
+        entries,
+        skipped,
+        mime_packages,
+        metainfo,
+        autostart,
+        search_providers,
+        dbus_services,
+    })
+}
 
-    // Desktop file parsing + icon lookup
-    let exec_regex = Regex::new(container_type.format_exec_regex_pattern().as_str()).unwrap();
-    let name_regex = Regex::new(container_type.format_name_regex_pattern().as_str()).unwrap();
+/// an entry that opens a shell in the container, in `terminal_command` if set or
+/// the desktop's default terminal otherwise. `None` if the container type can't be entered
+fn terminal_entry(
+    container_name: &str,
+    container_type: ContainerType,
+    terminal_command: Option<&str>,
+) -> Option<PendingEntry> {
+    let enter = container_type.format_enter(container_name);
+    if enter.is_empty() {
+        log::warn!("Container type {:?} has no terminal entry", container_type);
+        return None;
+    }
+    let icon_name = icon::terminal_name(container_name);
+    let (exec, terminal) = match terminal_command {
+        Some(command) => (format!("{} {}", command, enter), false),
+        None => (enter, true),
+    };
+    let file_text = format!(
+        "[Desktop Entry]\nType=Application\nName=Terminal ({0})\nComment=Open a shell in {0}\nExec={1}\nIcon={2}\nTerminal={3}\nCategories=System;{4};\n",
+        container_name, exec, icon_name, terminal, menu::category(container_name)
+    );
+    Some(PendingEntry {
+        appid: icon_name.clone(),
+        file_text,
+        icon_name,
+        icon_path: None,
+        icon_data: icon::terminal_icon(container_name),
+        missing_icon: None,
+        dbus_activatable: false,
+    })
+}
 
-    for entry_path in fs::read_dir(to_path.join("applications")).unwrap() {
-        let path_buf = entry_path.unwrap().path();
-        log::debug!("Looking at path: {:?}", path_buf);
-        if !path_buf.exists() {
-            log::warn!("Path {:?} doesn't exist!", path_buf);
+/// give every entry its icon, converted for the host. `lookup` finds the path and
+/// contents of an icon by name. entries whose icon can't be found or used get
+/// the placeholder
+fn attach_icons(
+    container_name: &str,
+    entries: &mut [PendingEntry],
+    lookup: impl Fn(&str) -> Option<(PathBuf, Vec<u8>)>,
+) {
+    for entry in entries.iter_mut() {
+        let converted = lookup(&entry.icon_name).and_then(|(icon_path, data)| {
+            log::debug!("Found icon path that matches! {:?}", icon_path);
+            let data = icon::convert_icon(&icon_path, data)?;
+            Some((icon_path, data))
+        });
+        match converted {
+            Some((icon_path, data)) => {
+                entry.icon_path = Some(icon_path);
+                entry.icon_data = data;
+            }
+            None => {
+                let placeholder_name = icon::placeholder_name(container_name);
+                if entry.icon_name != placeholder_name {
+                    log::warn!(
+                        "No usable icon '{}' for {}, using placeholder",
+                        entry.icon_name,
+                        entry.appid
+                    );
+                    // Registering the placeholder under the entry's icon name would
+                    // shadow an icon of that name the host theme has
+                    entry.file_text = replace_icon_key(&entry.file_text, &placeholder_name);
+                    entry.missing_icon =
+                        Some(std::mem::replace(&mut entry.icon_name, placeholder_name));
+                }
+                entry.icon_path = None;
+                entry.icon_data = icon::placeholder_icon(container_name);
+            }
+        }
+    }
+}
+
+/// whether any entry or icon registered last time is no longer exported
+fn has_removals(previous: &PushedEntries, entries: &[PendingEntry]) -> bool {
+    previous
+        .entries
+        .keys()
+        .any(|appid| !entries.iter().any(|entry| &entry.appid == appid))
+        || previous
+            .icons
+            .keys()
+            .any(|name| !entries.iter().any(|entry| &entry.icon_name == name))
+}
+
+/// register entries and icons with the sink, skipping those that haven't
+/// changed since they were last registered. what was added and what failed
+/// goes into `report`
+async fn push_client(
+    sink: &dyn EntrySink,
+    container_name: &str,
+    entries: &[PendingEntry],
+    pushed: &mut PushedEntries,
+    report: &mut ContainerReport,
+) {
+    for entry in entries.iter() {
+        let entry_hash = cache::hash_bytes(entry.file_text.as_bytes());
+        if pushed.entries.get(&entry.appid) != Some(&entry_hash) {
+            match sink
+                .add_entry(
+                    container_name,
+                    pushed.lifetime,
+                    &entry.appid,
+                    &entry.file_text,
+                )
+                .await
+            {
+                Ok(_) => {
+                    log::info!("Registered entry: {}", entry.appid);
+                    pushed.entries.insert(entry.appid.clone(), entry_hash);
+                    report.added.push(entry.appid.clone());
+                }
+                Err(e) => {
+                    log::error!("Error (entry): {}", e);
+                    report.failed.insert(entry.appid.clone(), e.to_string());
+                    continue;
+                }
+            }
+        }
+        let icon_hash = cache::hash_bytes(&entry.icon_data);
+        if pushed.icons.get(&entry.icon_name) != Some(&icon_hash) {
+            match sink
+                .add_icon(
+                    container_name,
+                    pushed.lifetime,
+                    &entry.icon_name,
+                    &entry.icon_data,
+                )
+                .await
+            {
+                Ok(_) => {
+                    log::info!("Registered icon: {}", entry.icon_name);
+                    pushed.icons.insert(entry.icon_name.clone(), icon_hash);
+                }
+                Err(e) => {
+                    log::error!("Error (icons): {}", e);
+                    report.failed.insert(entry.icon_name.clone(), e.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// get the contents of `files` (path to hash), fetching those missing from the
+/// cache from the container in a single stream
+async fn fetch_cached(
+    container_name: &str,
+    container_type: ContainerType,
+    cache: &Cache,
+    files: &HashMap<PathBuf, String>,
+    timeout: Duration,
+) -> Result<HashMap<PathBuf, Vec<u8>>, io::Error> {
+    let mut data = HashMap::new();
+    let mut missing = Vec::new();
+    for (path, hash) in files.iter() {
+        match cache.get(hash) {
+            Some(contents) => {
+                data.insert(path.clone(), contents);
+            }
+            None => missing.push(path.clone()),
+        }
+    }
+    log::debug!(
+        "{}: {} files cached, {} to fetch",
+        container_name,
+        data.len(),
+        missing.len()
+    );
+    for (path, contents) in
+        fetch_from_client(container_name, container_type, &missing, timeout).await?
+    {
+        cache.put(&contents)?;
+        data.insert(path, contents);
+    }
+    Ok(data)
+}
+
+/// hash every file directly in `dirs` in a single trip into the container
+async fn list_dirs(
+    container_name: &str,
+    container_type: ContainerType,
+    dirs: impl Iterator<Item = &PathBuf>,
+    timeout: Duration,
+) -> Result<Vec<(PathBuf, String)>, io::Error> {
+    let mut quoted: Vec<String> = dirs.map(|dir| shell_quote(dir.to_str().unwrap())).collect();
+    quoted.sort();
+    quoted.dedup();
+    if quoted.is_empty() {
+        return Ok(Vec::new());
+    }
+    let listing = run_in_client(
+        container_name,
+        container_type,
+        // Missing dirs make find fail, which must not look like the container failing
+        &format!(
+            "sh -c {}",
+            shell_quote(&format!(
+                "find -L {} -maxdepth 1 -type f -exec sha256sum {{}} + || true",
+                quoted.join(" ")
+            ))
+        ),
+        timeout,
+    )
+    .await?;
+    Ok(parse_hash_listing(&listing).collect())
+}
+
+/// the files of `listing` directly in `dirs` whose names end with `suffix`. for files
+/// with the same name the one in the earliest dir is used, the paths of the others
+/// are returned as shadowed
+fn rank_files(
+    listing: &[(PathBuf, String)],
+    dirs: &[PathBuf],
+    suffix: &str,
+) -> (HashMap<PathBuf, String>, Vec<PathBuf>) {
+    let mut files: HashMap<OsString, (usize, PathBuf, String)> = HashMap::new();
+    let mut shadowed = Vec::new();
+    for (path, hash) in listing.iter() {
+        let Some(rank) = dirs
+            .iter()
+            .position(|dir| path.parent() == Some(dir.as_path()))
+        else {
+            continue;
+        };
+        let Some(file_name) = path.file_name() else {
+            continue;
+        };
+        if !file_name
+            .to_str()
+            .is_some_and(|name| name.ends_with(suffix))
+        {
             continue;
         }
-        match read_to_string(&path_buf) {
+        if files
+            .get(file_name)
+            .is_none_or(|(other_rank, _, _)| rank < *other_rank)
+        {
+            let file = (rank, path.clone(), hash.clone());
+            if let Some((_, other, _)) = files.insert(file_name.to_owned(), file) {
+                shadowed.push(other);
+            }
+        } else {
+            shadowed.push(path.clone());
+        }
+    }
+    let files = files
+        .into_values()
+        .map(|(_, path, hash)| (path, hash))
+        .collect();
+    (files, shadowed)
+}
+
+/// parse the output of `sha256sum` into (path, hash) pairs
+fn parse_hash_listing(listing: &str) -> impl Iterator<Item = (PathBuf, String)> + '_ {
+    listing.lines().filter_map(|line| {
+        let (hash, path) = line.split_once("  ")?;
+        Some((PathBuf::from(path), hash.to_string()))
+    })
+}
+
+/// parse and rewrite desktop files fetched from the container, skipping
+/// entries that should not show up on the host
+fn read_entries(
+    container_name: &str,
+    container_type: ContainerType,
+    desktop_files: impl Iterator<Item = (PathBuf, Vec<u8>)>,
+    skipped: &mut Vec<SkippedEntry>,
+) -> Vec<PendingEntry> {
+    let name_regex = Regex::new(container_type.format_name_regex_pattern().as_str()).unwrap();
+
+    let mut entries = Vec::new();
+    for (path_buf, data) in desktop_files {
+        log::debug!("Looking at path: {:?}", path_buf);
+        match String::from_utf8(data) {
             Ok(file_text) => {
                 // run regex on it now
-                let file_text = exec_regex
-                    .replace_all(
-                        &file_text,
-                        container_type.format_desktop_exec(container_name),
-                    )
-                    .to_string();
+                let file_text = rewrite_exec(container_name, container_type, &file_text);
+                let file_text = menu::add_category(container_name, &file_text);
                 let file_text = name_regex
                     .replace_all(
                         &file_text,
@@ -151,63 +1274,62 @@ async fn set_up_client(
                         // We have a valid desktop entry
                         if entry.no_display() {
                             log::warn!("No display entry");
+                            skipped.push(SkippedEntry {
+                                path: path_buf.clone(),
+                                reason: SkipReason::NoDisplay,
+                            });
                             continue; // We don't want to push NoDisplay entries into our host
                         }
 
-                        match proxy
-                            .new_session_entry(&entry.appid, &file_text, owner)
-                            .await
-                        {
-                            Ok(_) => {
-                                log::info!("Daemon registered entry: {}", entry.appid);
-                                if let Some(icon_name) = entry.icon() {
-                                    if let Some(icon_path) = lookup_icon(
-                                        icon_name,
-                                        &to_path.join("icons"),
-                                        &to_path.join("pixmaps"),
-                                    ) {
-                                        log::debug!(
-                                            "Found icon path that matches! {:?}",
-                                            icon_path
-                                        );
-                                        match icon_path.extension().map(|p| p.to_str().unwrap()) {
-                                            Some("png" | "svg") => {
-                                                let file_bytes = read(icon_path).unwrap();
-                                                match proxy
-                                                    .new_session_icon(
-                                                        icon_name,
-                                                        file_bytes.as_slice(),
-                                                        owner,
-                                                    )
-                                                    .await
-                                                {
-                                                    Ok(_) => {
-                                                        log::info!(
-                                                            "Daemon registered icon: {}",
-                                                            icon_name
-                                                        );
-                                                    }
-                                                    Err(e) => {
-                                                        log::error!("Error (icons): {:?}", e);
-                                                    }
-                                                }
-                                            }
-                                            _ => {}
-                                        }
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                log::error!("Error (entry): {}", e);
-                            }
+                        // The host would activate the appid on its own bus instead of
+                        // running Exec, which never reaches the container
+                        let dbus_activatable =
+                            entry.desktop_entry("DBusActivatable") == Some("true");
+                        if dbus_activatable && entry.exec().is_none() {
+                            log::warn!(
+                                "{:?} is only D-Bus activatable, it has no Exec to launch",
+                                path_buf
+                            );
+                            skipped.push(SkippedEntry {
+                                path: path_buf.clone(),
+                                reason: SkipReason::NoExec,
+                            });
+                            continue;
                         }
+                        let file_text = if dbus_activatable {
+                            strip_dbus_activatable(&file_text)
+                        } else {
+                            file_text.clone()
+                        };
+
+                        // Entries without an icon get a generated placeholder
+                        let (file_text, icon_name) = match entry.icon() {
+                            Some(icon_name) => (file_text.clone(), icon_name.to_string()),
+                            None => {
+                                let icon_name = icon::placeholder_name(container_name);
+                                (insert_icon_key(&file_text, &icon_name), icon_name)
+                            }
+                        };
+                        entries.push(PendingEntry {
+                            appid: entry.appid.to_string(),
+                            file_text,
+                            icon_name,
+                            icon_path: None,
+                            icon_data: Vec::new(),
+                            missing_icon: None,
+                            dbus_activatable,
+                        });
                     }
                     Err(e) => {
                         log::error!(
                             "Could not read as valid desktop entry '{}' reason: {}",
                             file_text,
-                            e.to_string()
+                            e
                         );
+                        skipped.push(SkippedEntry {
+                            path: path_buf.clone(),
+                            reason: SkipReason::ParseError,
+                        });
                     }
                 }
             }
@@ -215,125 +1337,372 @@ async fn set_up_client(
                 log::error!(
                     "Could not read path {:?} to string. Reason: {}",
                     path_buf,
-                    e.to_string()
+                    e
                 );
+                skipped.push(SkippedEntry {
+                    path: path_buf,
+                    reason: SkipReason::InvalidUtf8,
+                });
             }
         }
     }
-    let _ = fs::remove_dir_all(&to_path.join("applications"));
-    let _ = fs::remove_dir_all(&to_path.join("icons"));
-    let _ = fs::remove_dir_all(&to_path.join("pixmaps"));
-    Ok(())
+    entries
 }
 
-fn lookup_icon(name: &str, base_path: &Path, pixmap_path: &Path) -> Option<PathBuf> {
-    WalkDir::new(base_path)
-        .follow_links(false)
-        .into_iter()
-        .filter_map(|e| match e {
-            Ok(d) => {
-                if d.path()
-                    .file_stem()
-                    .is_some_and(|stem| stem.to_str().unwrap() == name)
-                {
-                    Some(d)
-                } else {
-                    None
-                }
+/// find the best icon file inside the container for each of `names`, along with
+/// the hash of its contents. themed icons are preferred over pixmaps
+async fn resolve_icons(
+    container_name: &str,
+    container_type: ContainerType,
+    data_dirs: &[&Path],
+    names: &HashSet<&str>,
+    timeout: Duration,
+) -> Result<HashMap<String, (PathBuf, String)>, io::Error> {
+    let mut resolved = HashMap::new();
+    let mut absolute_paths = Vec::new();
+    let mut patterns = Vec::new();
+    for name in names.iter() {
+        if name.starts_with('/') {
+            // Icon= may already be an absolute path
+            absolute_paths.push(shell_quote(name));
+        } else {
+            patterns.push(format!("-name {}", shell_quote(&format!("{}.*", name))));
+        }
+    }
+    if absolute_paths.is_empty() && patterns.is_empty() {
+        return Ok(resolved);
+    }
+
+    // Absolute paths and themed icons are looked up in the same trip into the container
+    let pixmaps = Path::new("/usr/share/pixmaps");
+    let mut commands = Vec::new();
+    if !absolute_paths.is_empty() {
+        commands.push(format!("sha256sum {}", absolute_paths.join(" ")));
+    }
+    if !patterns.is_empty() {
+        let mut search_dirs: Vec<String> = data_dirs
+            .iter()
+            .map(|dir| shell_quote(dir.join("icons").to_str().unwrap()))
+            .collect();
+        search_dirs.push(shell_quote(pixmaps.to_str().unwrap()));
+        commands.push(format!(
+            "find -L {} -type f \\( {} \\) -exec sha256sum {{}} +",
+            search_dirs.join(" "),
+            patterns.join(" -o ")
+        ));
+    }
+    let found = run_in_client(
+        container_name,
+        container_type,
+        &format!("sh -c {}", shell_quote(&(commands.join("; ") + "; true"))),
+        timeout,
+    )
+    .await?;
+    let mut themed = Vec::new();
+    for (path, hash) in parse_hash_listing(&found) {
+        match path.to_str() {
+            Some(name) if names.contains(name) => {
+                resolved.insert(name.to_string(), (path, hash));
             }
-            Err(_) => None,
-        })
-        .max_by_key(|entry| {
-            if let Some(ext_os) = entry.path().extension() {
-                let ext = ext_os.to_str().unwrap();
-                match ext {
-                    "svg" => u32::MAX,
-                    "png" => {
-                        if let Some(p1) = entry.path().parent() {
-                            if let Some(p2) = p1.parent() {
-                                if let Some((a, _)) =
-                                    p2.file_name().unwrap().to_str().unwrap().split_once("x")
-                                {
-                                    if let Ok(size) = a.parse::<u32>() {
-                                        return size;
-                                    }
-                                }
-                            }
-                        }
-                        u32::MIN
-                    }
-                    _ => u32::MIN,
-                }
-            } else {
-                u32::MIN
+            _ => themed.push((path, hash)),
+        }
+    }
+
+    let mut best: HashMap<&str, ((bool, u32), PathBuf, String)> = HashMap::new();
+    for (path, hash) in themed {
+        let Some(name) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| names.get(stem))
+        else {
+            continue;
+        };
+        let Some(rank) = icon::icon_rank(&path) else {
+            continue;
+        };
+        let key = (!path.starts_with(pixmaps), rank);
+        if best
+            .get(name)
+            .is_none_or(|(best_key, _, _)| key > *best_key)
+        {
+            best.insert(name, (key, path, hash));
+        }
+    }
+    for (name, (_, path, hash)) in best {
+        log::debug!("Resolved icon {} to {:?}", name, path);
+        resolved.insert(name.to_string(), (path, hash));
+    }
+    Ok(resolved)
+}
+
+/// point the `Exec=` keys of `file_text` at the container. `TryExec=` is removed,
+/// the host would look for the binary on its own `PATH` and hide the entry. an
+/// `Exec=` that could not be rewritten is dropped rather than run on the host
+pub fn rewrite_exec(
+    container_name: &str,
+    container_type: ContainerType,
+    file_text: &str,
+) -> String {
+    let try_exec_regex = Regex::new(r"(?m)^[ \t]*TryExec[ \t]*=.*(\n|$)").unwrap();
+    let file_text = try_exec_regex.replace_all(file_text, "");
+    let exec_regex = Regex::new(container_type.format_exec_regex_pattern().as_str()).unwrap();
+    let replacement = container_type.format_desktop_exec(container_name);
+    let file_text = exec_regex.replace_all(&file_text, replacement.as_str());
+    let rewritten = replacement.split("${2}").next().unwrap_or_default();
+    file_text
+        .split_inclusive('\n')
+        .filter(|line| {
+            let is_exec = line
+                .split_once('=')
+                .is_some_and(|(key, _)| key.trim() == "Exec");
+            if is_exec && (rewritten.is_empty() || !line.starts_with(rewritten)) {
+                log::warn!(
+                    "{}: dropping Exec that doesn't run in the container: {}",
+                    container_name,
+                    line.trim_end()
+                );
+                return false;
             }
+            true
         })
-        .map(|entry| entry.path().to_path_buf())
-        .or(WalkDir::new(pixmap_path)
-            .follow_links(false)
-            .into_iter()
-            .find(|e| {
-                e.as_ref().is_ok_and(|d| {
-                    d.path()
-                        .file_stem()
-                        .is_some_and(|stem| stem.to_str().unwrap() == name)
-                })
-            })
-            .map(|f| f.unwrap().path().to_path_buf()))
+        .collect()
 }
 
-/// start the client
-fn start_client(
+/// rewrite autostart entries to run inside the container, leaving out those that
+/// are disabled with `Hidden=true`. returns their appids and file texts
+fn read_autostart(
     container_name: &str,
     container_type: ContainerType,
-) -> Result<Option<String>, io::Error> {
-    shell_command(&container_type.format_start(container_name), true)
+    files: impl Iterator<Item = (PathBuf, Vec<u8>)>,
+) -> Vec<(String, String)> {
+    let mut entries = Vec::new();
+    for (path, data) in files {
+        let Ok(file_text) = String::from_utf8(data) else {
+            log::warn!("Ignoring autostart entry {:?}, not valid utf-8", path);
+            continue;
+        };
+        let file_text = rewrite_exec(container_name, container_type, &file_text);
+        match DesktopEntry::decode(&path, &file_text) {
+            Ok(entry) if entry.desktop_entry("Hidden") == Some("true") => {
+                log::debug!("Skipping disabled autostart entry {:?}", path)
+            }
+            Ok(entry) => entries.push((entry.appid.to_string(), file_text.clone())),
+            Err(e) => log::error!("Could not read autostart entry {:?}: {}", path, e),
+        }
+    }
+    entries
+}
+
+/// remove the `DBusActivatable=` key from `file_text`
+fn strip_dbus_activatable(file_text: &str) -> String {
+    let regex = Regex::new(r"(?m)^[ \t]*DBusActivatable[ \t]*=.*(\n|$)").unwrap();
+    regex.replace_all(file_text, "").to_string()
+}
+
+/// add an `Icon=` key to the `[Desktop Entry]` group of `file_text`
+fn insert_icon_key(file_text: &str, icon_name: &str) -> String {
+    file_text.replacen(
+        "[Desktop Entry]",
+        &format!("[Desktop Entry]\nIcon={}", icon_name),
+        1,
+    )
+}
+
+/// point the `Icon=` key of the main group at `icon_name`
+fn replace_icon_key(file_text: &str, icon_name: &str) -> String {
+    let icon_regex = Regex::new(r"(?m)^[ \t]*Icon[ \t]*=[^\r\n]*").unwrap();
+    icon_regex
+        .replace(file_text, regex::NoExpand(&format!("Icon={}", icon_name)))
+        .to_string()
+}
+
+/// start the client, retrying with exponential backoff until it reports that it's ready
+pub async fn start_client(
+    container_name: &str,
+    container_type: ContainerType,
+    settings: &ContainerSettings,
+) -> Result<(), ClientSetupError> {
+    let timeout = Duration::from_secs(settings.timeout);
+    let mut backoff = Duration::from_secs(settings.backoff);
+    for attempt in 0..=settings.retries {
+        if attempt > 0 {
+            log::warn!(
+                "{} is not ready, retrying in {:?} ({}/{})",
+                container_name,
+                backoff,
+                attempt,
+                settings.retries
+            );
+            task::sleep(backoff).await;
+            backoff *= 2;
+        }
+        match run_command(&container_type.format_start(container_name), timeout).await {
+            Ok(out) if out.status.success() => return Ok(()),
+            Ok(out) => {
+                log::warn!(
+                    "Starting {} exited with {}: '{}'",
+                    container_name,
+                    out.status,
+                    String::from_utf8_lossy(&out.stderr).trim()
+                );
+            }
+            Err(e) => {
+                log::warn!("Starting {} failed: {}", container_name, e);
+            }
+        }
+    }
+    Err(ClientSetupError::NotReady(settings.retries + 1))
 }
 
 /// run a command on the container of choice
-fn run_in_client(
+async fn run_in_client(
     container_name: &str,
     container_type: ContainerType,
     command: &str,
-    wait_for_output: bool,
-) -> Result<Option<String>, io::Error> {
+    timeout: Duration,
+) -> Result<String, io::Error> {
     shell_command(
         &container_type.format_exec(container_name, command),
-        wait_for_output,
+        timeout,
     )
+    .await
 }
 
-/// copy a folder from the container of choice
-fn copy_from_client(
+/// fetch files and folders from the container of choice in a single tar stream.
+/// returns the contents of every regular file found, keyed by its path in the container
+async fn fetch_from_client(
     container_name: &str,
     container_type: ContainerType,
-    from: &Path,
-    to: &Path,
-) -> Result<Option<String>, io::Error> {
-    shell_command(&container_type.format_copy(container_name, from, to), true)
+    paths: &[PathBuf],
+    timeout: Duration,
+) -> Result<HashMap<PathBuf, Vec<u8>>, io::Error> {
+    let mut files = HashMap::new();
+    if paths.is_empty() {
+        return Ok(files);
+    }
+    let archive = shell_command_bytes(
+        &container_type.format_archive(container_name, paths),
+        timeout,
+    )
+    .await?;
+    for entry in tar::Archive::new(archive.as_slice()).entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = Path::new("/").join(entry.path()?);
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut data)?;
+        files.insert(path, data);
+    }
+    log::debug!("Fetched {} files from {}", files.len(), container_name);
+    Ok(files)
+}
+
+async fn shell_command(command: &str, timeout: Duration) -> Result<String, io::Error> {
+    let stdout = shell_command_bytes(command, timeout).await?;
+    log::debug!(
+        "Output completed! stdout: '{}'",
+        String::from_utf8_lossy(&stdout)
+    );
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+/// run `command`, failing if it exits with an error. commands in the container are
+/// written to succeed, so an error means the container couldn't run them and their
+/// output can't be taken for the container's files
+async fn shell_command_bytes(command: &str, timeout: Duration) -> Result<Vec<u8>, io::Error> {
+    let out = run_command(command, timeout).await?;
+    if !out.status.success() {
+        return Err(io::Error::other(format!(
+            "command exited with {}: '{}'",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    Ok(out.stdout)
 }
 
-fn shell_command(command: &str, wait_for_output: bool) -> Result<Option<String>, io::Error> {
+/// run `command` through `sh`, killing it if it doesn't finish within `timeout`
+async fn run_command(command: &str, timeout: Duration) -> Result<Output, io::Error> {
     log::debug!("Full command: sh -c '{}'", command);
-    if wait_for_output {
-        let out = Command::new("sh")
-            .arg("-c")
-            .arg(format!("{}", command))
-            .output()
-            .expect(&format!("Command {} failed", command));
-        log::debug!(
-            "Output completed! stdout: '{}', stderr: '{}'",
-            String::from_utf8(out.stdout.clone()).unwrap(),
-            String::from_utf8(out.stderr).unwrap()
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .kill_on_drop(true)
+        .output();
+    // On timeout the output future is dropped, which kills the child
+    let out = future::timeout(timeout, output).await.map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("command timed out after {:?}", timeout),
+        )
+    })??;
+    log::debug!(
+        "Command exited with {}, stderr: '{}'",
+        out.status,
+        String::from_utf8_lossy(&out.stderr)
+    );
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAUNCH: &str = "Exec=container-desktop-entries launch box -- ";
+
+    #[test]
+    fn rewrites_indented_exec() {
+        let text = "[Desktop Entry]\n  Exec = /usr/bin/app %U\n\tTryExec=app\n";
+        assert_eq!(
+            rewrite_exec("box", ContainerType::Toolbox, text),
+            format!("[Desktop Entry]\n{}/usr/bin/app %U\n", LAUNCH)
+        );
+    }
+
+    #[test]
+    fn removes_try_exec() {
+        let text = "[Desktop Entry]\nTryExec=app\nExec=app\n";
+        assert_eq!(
+            rewrite_exec("box", ContainerType::Toolbox, text),
+            format!("[Desktop Entry]\n{}app\n", LAUNCH)
+        );
+    }
+
+    #[test]
+    fn rewrites_actions() {
+        let text = "[Desktop Entry]\nExec=app\n\n[Desktop Action new]\nName=New\nExec=app --new\n";
+        assert_eq!(
+            rewrite_exec("box", ContainerType::Toolbox, text),
+            format!(
+                "[Desktop Entry]\n{0}app\n\n[Desktop Action new]\nName=New\n{0}app --new\n",
+                LAUNCH
+            )
+        );
+    }
+
+    #[test]
+    fn keeps_crlf() {
+        let text = "[Desktop Entry]\r\nTryExec=app\r\nExec=app\r\nName=App\r\n";
+        assert_eq!(
+            rewrite_exec("box", ContainerType::Toolbox, text),
+            format!("[Desktop Entry]\r\n{}app\r\nName=App\r\n", LAUNCH)
+        );
+    }
+
+    #[test]
+    fn strips_indented_dbus_activatable() {
+        let text = "[Desktop Entry]\n DBusActivatable = true\nName=App\n";
+        assert_eq!(strip_dbus_activatable(text), "[Desktop Entry]\nName=App\n");
+    }
+
+    #[test]
+    fn drops_exec_it_cannot_rewrite() {
+        let text = "[Desktop Entry]\nExec=app\n";
+        assert_eq!(
+            rewrite_exec("box", ContainerType::Unknown, text),
+            "[Desktop Entry]\n"
         );
-        Ok(Some(String::from_utf8(out.stdout).unwrap()))
-    } else {
-        let child_handle = Command::new("sh")
-            .arg("-c")
-            .arg(format!("{}", command))
-            .spawn()
-            .expect(&format!("Command {} failed", command));
-        log::debug!("Started child process with pid {}", child_handle.id());
-        Ok(None)
     }
 }
@@ -0,0 +1,210 @@
+use zbus::{fdo, interface, proxy, SignalContext};
+
+use crate::{
+    protocol::Bundle,
+    server::{ContainerReport, ReportStatus, Server},
+};
+
+/// well known name the service is published under on the session bus
+pub const SERVICE_NAME: &str = "io.ryanabx.ContainerDesktopEntries";
+/// object path of the service
+pub const SERVICE_PATH: &str = "/io/ryanabx/ContainerDesktopEntries";
+
+/// client side of the interface, used by the CLI when the service is running
+#[proxy(
+    interface = "io.ryanabx.ContainerDesktopEntries",
+    default_service = "io.ryanabx.ContainerDesktopEntries",
+    default_path = "/io/ryanabx/ContainerDesktopEntries"
+)]
+trait Service {
+    fn sync(&self, container: &str) -> zbus::Result<()>;
+
+    fn sync_all(&self) -> zbus::Result<()>;
+
+    fn sync_report(&self, containers: &[&str]) -> zbus::Result<String>;
+
+    fn remove(&self, container: &str) -> zbus::Result<()>;
+
+    fn list_containers(&self) -> zbus::Result<Vec<(String, String)>>;
+}
+
+/// D-Bus interface for triggering syncs and removals while the service runs
+pub struct ContainerDesktopEntries {
+    server: Server,
+}
+
+impl ContainerDesktopEntries {
+    pub fn new(server: Server) -> Self {
+        Self { server }
+    }
+
+    /// whether clients claiming to be `container` are accepted without checking
+    pub fn accepts_unverified(&self, container: &str) -> bool {
+        self.server.containers.settings(container).unverified_client
+    }
+
+    /// register the entries a client sent, then signal what changed
+    pub async fn receive_bundle(
+        &mut self,
+        ctxt: &SignalContext<'_>,
+        bundle: Bundle,
+    ) -> ContainerReport {
+        let container = bundle.container.name.clone();
+        let report = self
+            .server
+            .push_bundle(bundle)
+            .await
+            .unwrap_or_else(|e| ContainerReport {
+                container,
+                status: ReportStatus::Failed,
+                error: Some(e.to_string()),
+                ..Default::default()
+            });
+        signal_reports(ctxt, std::slice::from_ref(&report)).await;
+        report
+    }
+
+    /// sync every container, then signal what changed
+    pub async fn sync_all_and_signal(&mut self, ctxt: &SignalContext<'_>) -> fdo::Result<()> {
+        let reports = self.server.sync_all().await.map_err(to_fdo)?;
+        signal_reports(ctxt, &reports).await;
+        Ok(())
+    }
+}
+
+#[interface(name = "io.ryanabx.ContainerDesktopEntries")]
+impl ContainerDesktopEntries {
+    /// Sync method: fetch the entries of one configured container again
+    async fn sync(
+        &mut self,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+        container: &str,
+    ) -> fdo::Result<()> {
+        self.check_configured(container)?;
+        let reports = self
+            .server
+            .sync(&[container.to_string()])
+            .await
+            .map_err(to_fdo)?;
+        signal_reports(&ctxt, &reports).await;
+        Ok(())
+    }
+
+    /// SyncAll method: fetch the entries of every configured container again
+    async fn sync_all(
+        &mut self,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        self.sync_all_and_signal(&ctxt).await
+    }
+
+    /// SyncReport method: sync `containers`, or every container if empty, and return
+    /// their reports as JSON
+    async fn sync_report(
+        &mut self,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+        containers: Vec<String>,
+    ) -> fdo::Result<String> {
+        for container in containers.iter() {
+            self.check_configured(container)?;
+        }
+        let reports = if containers.is_empty() {
+            self.server.sync_all().await
+        } else {
+            self.server.sync(&containers).await
+        }
+        .map_err(to_fdo)?;
+        signal_reports(&ctxt, &reports).await;
+        serde_json::to_string_pretty(&reports).map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Remove method: remove the entries of a container until it is synced again
+    async fn remove(
+        &mut self,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+        container: &str,
+    ) -> fdo::Result<()> {
+        self.check_configured(container)?;
+        let report = self.server.remove(container).await.map_err(to_fdo)?;
+        signal_reports(&ctxt, &[report]).await;
+        Ok(())
+    }
+
+    /// ListContainers method: name and type of every configured container
+    async fn list_containers(&self) -> Vec<(String, String)> {
+        self.server
+            .containers
+            .containers
+            .iter()
+            .map(|(name, container_type)| (name.clone(), format!("{:?}", container_type)))
+            .collect()
+    }
+
+    /// EntryAdded signal: an entry was registered or updated
+    #[zbus(signal)]
+    async fn entry_added(
+        ctxt: &SignalContext<'_>,
+        container: &str,
+        appid: &str,
+    ) -> zbus::Result<()>;
+
+    /// EntryRemoved signal: an entry is no longer registered
+    #[zbus(signal)]
+    async fn entry_removed(
+        ctxt: &SignalContext<'_>,
+        container: &str,
+        appid: &str,
+    ) -> zbus::Result<()>;
+
+    /// SyncFailed signal: a container could not be synced
+    #[zbus(signal)]
+    async fn sync_failed(
+        ctxt: &SignalContext<'_>,
+        container: &str,
+        error: &str,
+    ) -> zbus::Result<()>;
+}
+
+impl ContainerDesktopEntries {
+    fn check_configured(&self, container: &str) -> fdo::Result<()> {
+        if self.server.configured().contains(container) {
+            Ok(())
+        } else {
+            Err(fdo::Error::InvalidArgs(format!(
+                "{} is not a configured container",
+                container
+            )))
+        }
+    }
+}
+
+/// emit the signals describing `reports`
+async fn signal_reports(ctxt: &SignalContext<'_>, reports: &[ContainerReport]) {
+    for report in reports.iter() {
+        for appid in report.added.iter() {
+            if let Err(e) =
+                ContainerDesktopEntries::entry_added(ctxt, &report.container, appid).await
+            {
+                log::warn!("could not emit EntryAdded: {}", e);
+            }
+        }
+        for appid in report.removed.iter() {
+            if let Err(e) =
+                ContainerDesktopEntries::entry_removed(ctxt, &report.container, appid).await
+            {
+                log::warn!("could not emit EntryRemoved: {}", e);
+            }
+        }
+        if let Some(error) = report.error.as_ref() {
+            if let Err(e) =
+                ContainerDesktopEntries::sync_failed(ctxt, &report.container, error).await
+            {
+                log::warn!("could not emit SyncFailed: {}", e);
+            }
+        }
+    }
+}
+
+fn to_fdo(e: crate::server::ClientSetupError) -> fdo::Error {
+    fdo::Error::Failed(e.to_string())
+}
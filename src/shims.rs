@@ -0,0 +1,82 @@
+use std::{
+    collections::HashSet,
+    env, fs, io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{manifest::FileManifest, ContainerList};
+
+/// where shims go when the config doesn't say, usually `~/.local/bin`
+fn default_bin_dir() -> Result<PathBuf, io::Error> {
+    let home = env::var("HOME").map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+    Ok(Path::new(&home).join(".local").join("bin"))
+}
+
+/// write a shim into the bin dir for every binary the containers in `names` export,
+/// replacing the ones written before. shims of containers that left the config are
+/// removed as well. files in the bin dir that aren't ours are never overwritten
+pub fn export_shims(containers: &ContainerList, names: &HashSet<&str>) -> Result<(), io::Error> {
+    let bin_dir = match containers.bin_dir.clone() {
+        Some(bin_dir) => bin_dir,
+        None => default_bin_dir()?,
+    };
+    let mut manifest = FileManifest::load("shims")?;
+    for container_name in manifest.container_names() {
+        if !containers
+            .containers
+            .iter()
+            .any(|(name, _)| *name == container_name)
+        {
+            manifest.remove_container(&container_name);
+        }
+    }
+    for container_name in names.iter() {
+        manifest.remove_container(container_name);
+    }
+    for (container_name, container_type) in containers
+        .containers
+        .iter()
+        .filter(|(name, _)| names.contains(name.as_str()))
+    {
+        for binary in containers.settings(container_name).binaries.iter() {
+            if binary.is_empty() || binary.contains('/') {
+                log::error!("{}: invalid binary name {:?}", container_name, binary);
+                continue;
+            }
+            let body = container_type.format_shim(container_name, binary);
+            if body.is_empty() {
+                log::error!(
+                    "Container type {:?} does not support binary shims",
+                    container_type
+                );
+                break;
+            }
+            let path = bin_dir.join(binary);
+            if path.symlink_metadata().is_ok() {
+                log::warn!(
+                    "{}: not exporting {}, {:?} already exists",
+                    container_name,
+                    binary,
+                    path
+                );
+                continue;
+            }
+            let script = format!(
+                "#!/bin/sh\n# {} from {}, exported by container-desktop-entries\n{}\n",
+                binary, container_name, body
+            );
+            manifest.write(container_name, &path, script.as_bytes())?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+            log::info!("{}: exported {} to {:?}", container_name, binary, path);
+        }
+    }
+    manifest.save()
+}
+
+/// remove every shim written for `container_name`
+pub fn remove_shims(container_name: &str) -> Result<(), io::Error> {
+    let mut manifest = FileManifest::load("shims")?;
+    manifest.remove_container(container_name);
+    manifest.save()
+}
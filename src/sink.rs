@@ -0,0 +1,220 @@
+use std::{io, path::PathBuf};
+
+use async_trait::async_trait;
+use zbus::{fdo::DBusProxy, names::BusName, Connection};
+
+use crate::{
+    desktop_entry::DesktopEntryProxy,
+    export_sink::ExportSink,
+    local_sink::{data_home, LocalSink},
+    mime,
+    server::ClientSetupError,
+    Backend, EntryLifetime,
+};
+
+/// well known name of desktop-entry-daemon
+const DAEMON_NAME: &str = "io.ryanabx.DesktopEntry";
+
+/// somewhere container entries and icons end up on the host
+#[async_trait]
+pub trait EntrySink: Send + Sync {
+    /// register a desktop entry for `container_name`
+    async fn add_entry(
+        &self,
+        container_name: &str,
+        lifetime: EntryLifetime,
+        appid: &str,
+        file_text: &str,
+    ) -> Result<(), ClientSetupError>;
+
+    /// register an icon for `container_name`. `data` is png or svg
+    async fn add_icon(
+        &self,
+        container_name: &str,
+        lifetime: EntryLifetime,
+        name: &str,
+        data: &[u8],
+    ) -> Result<(), ClientSetupError>;
+
+    /// remove everything registered for `container_name` with `lifetime`. fails
+    /// without removing anything if the sink can't remove entries with `lifetime`
+    async fn remove(
+        &self,
+        container_name: &str,
+        lifetime: EntryLifetime,
+    ) -> Result<(), ClientSetupError>;
+
+    /// called when nothing has been registered yet this session, to clean up
+    /// anything left behind by older versions
+    async fn remove_legacy(&self) -> Result<(), ClientSetupError> {
+        Ok(())
+    }
+
+    /// desktop file id an entry of `container_name` ends up with on the host
+    fn desktop_id(&self, _container_name: &str, appid: &str) -> String {
+        format!("{}.desktop", appid)
+    }
+
+    /// name an icon of `container_name` ends up with on the host
+    fn icon_name(&self, _container_name: &str, name: &str) -> String {
+        name.to_string()
+    }
+
+    /// `file_text` of an entry of `container_name` as it ends up on the host
+    fn entry_text(&self, _container_name: &str, file_text: &str) -> String {
+        file_text.to_string()
+    }
+
+    /// data dir that the files of `container_name` other than entries and icons, like
+    /// metainfo and mime packages, are installed into. `$XDG_DATA_HOME` by default
+    fn data_dir(&self, _container_name: &str) -> Result<PathBuf, io::Error> {
+        data_home()
+    }
+
+    /// called when the mime packages installed into `data_dir` changed
+    fn mime_changed(&self, _container_name: &str) -> Result<(), io::Error> {
+        mime::update_database(&data_home()?.join("mime"));
+        Ok(())
+    }
+
+    /// called once every container has been synced or removed
+    async fn finish(&self) -> Result<(), ClientSetupError> {
+        Ok(())
+    }
+}
+
+/// open the sink for `backend`. `Backend::Auto` uses desktop-entry-daemon when
+/// it is available on the session bus, and writes files directly otherwise
+pub async fn open_sink(
+    backend: Backend,
+    owner: &str,
+) -> Result<Box<dyn EntrySink>, ClientSetupError> {
+    let backend = match backend {
+        Backend::Auto => {
+            if daemon_available().await {
+                Backend::Daemon
+            } else {
+                log::warn!("desktop-entry-daemon is not available, writing entries to files");
+                Backend::Local
+            }
+        }
+        backend => backend,
+    };
+    Ok(match backend {
+        Backend::Local => Box::new(LocalSink::new()?),
+        Backend::Export => Box::new(ExportSink::new()?),
+        _ => Box::new(DaemonSink::new(owner).await?),
+    })
+}
+
+/// whether desktop-entry-daemon is running or can be started on the session bus
+async fn daemon_available() -> bool {
+    let Ok(connection) = Connection::session().await else {
+        return false;
+    };
+    let Ok(dbus) = DBusProxy::new(&connection).await else {
+        return false;
+    };
+    let name = BusName::from_static_str(DAEMON_NAME).unwrap();
+    if dbus.name_has_owner(name).await.unwrap_or(false) {
+        return true;
+    }
+    dbus.list_activatable_names()
+        .await
+        .is_ok_and(|names| names.iter().any(|name| name.as_str() == DAEMON_NAME))
+}
+
+/// registers entries with desktop-entry-daemon, each container under its own owner
+pub struct DaemonSink {
+    proxy: DesktopEntryProxy<'static>,
+    owner: String,
+}
+
+impl DaemonSink {
+    pub async fn new(owner: &str) -> Result<Self, ClientSetupError> {
+        let connection = Connection::session().await?;
+        Ok(Self {
+            proxy: DesktopEntryProxy::new(&connection).await?,
+            owner: owner.to_string(),
+        })
+    }
+
+    /// owner that a container's entries and icons are registered under
+    fn client_owner(&self, container_name: &str) -> String {
+        format!("{}/{}", self.owner, container_name)
+    }
+}
+
+#[async_trait]
+impl EntrySink for DaemonSink {
+    async fn add_entry(
+        &self,
+        container_name: &str,
+        lifetime: EntryLifetime,
+        appid: &str,
+        file_text: &str,
+    ) -> Result<(), ClientSetupError> {
+        let owner = self.client_owner(container_name);
+        match lifetime {
+            EntryLifetime::Session => {
+                self.proxy
+                    .new_session_entry(appid, file_text, &owner)
+                    .await?
+            }
+            EntryLifetime::Persistent => {
+                self.proxy
+                    .new_persistent_entry(appid, file_text, &owner)
+                    .await?
+            }
+            EntryLifetime::Process => self.proxy.new_process_entry(appid, file_text).await?,
+        }
+        Ok(())
+    }
+
+    async fn add_icon(
+        &self,
+        container_name: &str,
+        lifetime: EntryLifetime,
+        name: &str,
+        data: &[u8],
+    ) -> Result<(), ClientSetupError> {
+        let owner = self.client_owner(container_name);
+        match lifetime {
+            EntryLifetime::Session => self.proxy.new_session_icon(name, data, &owner).await?,
+            EntryLifetime::Persistent => self.proxy.new_persistent_icon(name, data, &owner).await?,
+            EntryLifetime::Process => self.proxy.new_process_icon(name, data).await?,
+        }
+        Ok(())
+    }
+
+    async fn remove(
+        &self,
+        container_name: &str,
+        lifetime: EntryLifetime,
+    ) -> Result<(), ClientSetupError> {
+        let owner = self.client_owner(container_name);
+        match lifetime {
+            EntryLifetime::Session => self.proxy.remove_session_owner(&owner).await?,
+            EntryLifetime::Persistent => self.proxy.remove_persistent_owner(&owner).await?,
+            EntryLifetime::Process => {
+                // Process entries have no owner to remove them by, they only go away
+                // when we exit
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!(
+                        "Process entries of {} can't be removed until the service exits",
+                        container_name
+                    ),
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_legacy(&self) -> Result<(), ClientSetupError> {
+        // Older versions registered every container under one owner
+        self.proxy.remove_session_owner(&self.owner).await?;
+        Ok(())
+    }
+}